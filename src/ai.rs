@@ -1,54 +1,123 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
 
 use async_openai::{
+    config::OpenAIConfig,
+    error::OpenAIError,
     types::{
-        ChatCompletionRequestSystemMessage, ChatCompletionRequestUserMessage,
-        CreateChatCompletionRequestArgs, ResponseFormat, ResponseFormatJsonSchema,
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestSystemMessage,
+        ChatCompletionRequestUserMessage, CreateChatCompletionRequest,
+        CreateChatCompletionRequestArgs, CreateChatCompletionResponse, ResponseFormat,
+        ResponseFormatJsonSchema,
     },
     Client,
 };
+use backoff::ExponentialBackoffBuilder;
 use chrono::NaiveDate;
 use eyre::Context;
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::{preferences::Preferences, CalendarDayItems, DishItem};
+use crate::{
+    config::{self, Config, ConfirmationMode, Objective},
+    preferences::{self, Preferences},
+    recording,
+    rules::{self, Constraint},
+    status, tags, CalendarDayItems, DishItem, Nutrition,
+};
+
+const SYSTEM_PROMPT: &str = "You are personal meal assistant. You have to select meals for the user. Figure out what the user wants to eat from the menu. Use historic data to figure out user preferences.";
+const AVOID_REPEATS_PROMPT: &str =
+    " Try not to pick the same meal as the user had in the last days.";
+const ALLOW_REPEATS_PROMPT: &str = " Picking a meal the user had in the last days is fine, don't penalize repeating dishes the user likes.";
+const NUTRITION_GOALS_PROMPT: &str = " nutrition of each option is per portion; daily_nutrition_goals are the user's targets for the whole day, prefer combinations of meals that bring the day's total close to them.";
+const RULES_PROMPT: &str = " rules are the user's own limits on how often to eat some dishes, follow them strictly; a rule needed today means at least one meal must match it.";
+const CURRENT_SELECTION_PROMPT: &str = " current_dish_id is the dish the user has selected now, keep it unless another option clearly suits the user better.";
+const MISSING_INGREDIENTS_PROMPT: &str = " Some dishes come without ingredients. When that limits how sure you are about a pick, say in its reason that ingredient data was missing.";
+
+/// Key of chat completions in a recording of the run
+const AI_RECORDING_KEY: &str = "POST openai chat completions";
+
+/// Empty responses are usually transient, so the request is repeated once
+const EMPTY_RESPONSE_ATTEMPTS: u32 = 2;
+/// Answers that don't parse as `AiResponse` are asked again, with the error, up to this many times
+const INVALID_RESPONSE_ATTEMPTS: u32 = 3;
+
+const AI_RETRY_INITIAL_INTERVAL: Duration = Duration::from_secs(2);
+/// A timed out request is sent once more before giving up
+const TIMEOUT_ATTEMPTS: u32 = 2;
+
+/// Rate limits are per minute, longer waits usually mean the quota is gone
+const AI_RETRY_MAX_ELAPSED: Duration = Duration::from_secs(180);
+
+/// Checked before `OPENAI_API_KEY` so the tool can use a dedicated key
+const API_KEY_ENV: &str = "POWERMEAL_OPENAI_API_KEY";
 
 #[derive(Debug, Serialize)]
 pub struct SelectDishQuestion {
     pub user_changes: Vec<UserAdjustment>,
+    pub preference_tags: IndexMap<String, usize>,
+    pub variety_weight: f32,
+    pub avoid_ingredients: Vec<String>,
+    pub cuisine_preferences: Vec<String>,
+    pub flavor_profiles: Vec<String>,
     pub last_days_choices: IndexMap<String, Vec<AiMenuDietOption>>,
     pub dish_items: Vec<AiDishItem>,
     pub menu_date: NaiveDate,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub daily_nutrition_goals: Option<Nutrition>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct UserAdjustment {
     pub from: String,
     pub to: String,
     pub reason: Option<String>,
     pub date: NaiveDate,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct AiResponse {
     pub reasoning: Vec<String>,
     pub selections: HashMap<String, ResponseItem>,
+    /// Made by [`heuristic_selection`] rather than the AI
+    #[serde(default)]
+    pub heuristic: bool,
 }
 
 #[derive(Debug, Serialize)]
 pub struct AiDishItem {
     pub id: String,
     pub meal_type: String,
+    pub variety_weight: f32,
     pub options: Vec<AiMenuDietOption>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_dish_id: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct AiMenuDietOption {
     pub name: String,
     pub ingredients: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allergens: Vec<String>,
     pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rating: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub surcharge: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nutrition: Option<Nutrition>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -56,47 +125,277 @@ pub struct ResponseItem {
     pub dish_id: String,
     pub reason: String,
     pub analysis: HashMap<String, String>,
+    /// Why each other option lost to `dish_id`
+    #[serde(default)]
+    pub comparison: HashMap<String, String>,
+    /// Set when `dish_id` was eaten recently but no fresh option was acceptable
+    #[serde(default)]
+    pub repeat_unavoidable: bool,
 }
 
-pub async fn select_dish(
-    date: NaiveDate,
-    dish_items: &Vec<DishItem>,
-    last_days_choices: &IndexMap<String, CalendarDayItems>,
-) -> eyre::Result<AiResponse> {
-    let client = Client::new();
+pub fn api_key() -> eyre::Result<String> {
+    // replayed AI answers don't need a key
+    if recording::replaying() {
+        return Ok(String::new());
+    }
+    [API_KEY_ENV, "OPENAI_API_KEY"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|key| !key.is_empty()))
+        .ok_or_else(|| {
+            eyre::eyre!(
+                "OpenAI API key is not set.\n\
+                 Create one at https://platform.openai.com/api-keys and export it as \
+                 {API_KEY_ENV} (or OPENAI_API_KEY) before running."
+            )
+        })
+}
+
+/// Replaces the built-in opening of the system prompt, including the advice on repeats
+const SYSTEM_PROMPT_FILE: &str = "system_prompt.txt";
+
+/// Opening of the system prompt from `SYSTEM_PROMPT_FILE` in the config directory,
+/// None to use the built-in one
+fn custom_system_prompt() -> Option<String> {
+    let path = preferences::config_dir().join(SYSTEM_PROMPT_FILE);
+    if !path.exists() {
+        return None;
+    }
+    match std::fs::read_to_string(&path) {
+        Ok(prompt) if prompt.trim().is_empty() => {
+            tracing::warn!("{} is empty, using the built-in prompt", path.display());
+            None
+        }
+        Ok(prompt) => Some(prompt.trim().to_string()),
+        Err(e) => {
+            tracing::warn!(
+                "Can't read {}, using the built-in prompt: {e}",
+                path.display()
+            );
+            None
+        }
+    }
+}
+
+fn system_prompt(config: &Config, dish_items: &[&DishItem]) -> String {
+    let mut prompt = match custom_system_prompt() {
+        Some(custom) => custom,
+        None => {
+            let mut prompt = SYSTEM_PROMPT.to_string();
+            prompt.push_str(if config.allow_repeats {
+                ALLOW_REPEATS_PROMPT
+            } else {
+                AVOID_REPEATS_PROMPT
+            });
+            prompt
+        }
+    };
+    // the rest explains the data sent, so it applies to custom prompts too
+    prompt.push_str(" preference_tags counts recurring reasons behind user changes.");
+    prompt.push_str(" avoid_ingredients were eaten very recently, prefer dishes without them today when a reasonable alternative exists.");
+    prompt.push_str(" allergens are declared by the caterer, treat a user_change avoiding an ingredient as also avoiding dishes declaring it as an allergen.");
+    prompt.push_str(" cuisine_preferences and flavor_profiles are broad taste directions stated by the user, apply them to dishes not seen before; learned user_changes win when they disagree.");
+    prompt.push_str(" variety_weight (0 to 1) sets how much to prioritize variety over strict adherence to user preferences: 0 means stick to what the user is known to like, 1 means maximize novelty. Each dish item has its own variety_weight that takes precedence over the global one for that meal.");
+
+    let objective = config.objective;
+    let options = || dish_items.iter().flat_map(|dish_item| dish_item.options());
+    if !config.rules.is_empty() {
+        prompt.push_str(RULES_PROMPT);
+    }
+    if config.show_current_selection {
+        prompt.push_str(CURRENT_SELECTION_PROMPT);
+    }
+    if options().any(|option| option.lacks_ingredients()) {
+        prompt.push_str(MISSING_INGREDIENTS_PROMPT);
+    }
+    if config.nutrition_goals.is_some() && options().any(|option| option.nutrition().is_some()) {
+        prompt.push_str(NUTRITION_GOALS_PROMPT);
+    }
+    match objective {
+        Objective::Taste => {}
+        Objective::TopRated if options().any(|option| option.rating().is_some()) => {
+            prompt
+                .push_str(" Among options the user would enjoy, prefer those with higher rating.");
+        }
+        Objective::Cheapest
+            if options().any(|option| option.price().or(option.surcharge()).is_some()) =>
+        {
+            prompt.push_str(
+                " Among options the user would enjoy, prefer those with lower price and surcharge.",
+            );
+        }
+        Objective::HighProtein if options().any(|option| option.protein().is_some()) => {
+            prompt.push_str(" Among options the user would enjoy, prefer those with more protein.");
+        }
+        _ => {
+            tracing::info!("Menu has no data for objective {objective:?}, selecting by taste only")
+        }
+    }
+    prompt
+}
+
+/// The client retries rate limited (429) and server error responses with
+/// exponential backoff on its own, this caps how long it keeps trying. Each
+/// HTTP request is bounded by `ai_timeout_secs`, so waiting out a rate limit
+/// doesn't count as a stalled answer.
+fn client() -> eyre::Result<Client<OpenAIConfig>> {
+    let backoff = ExponentialBackoffBuilder::new()
+        .with_initial_interval(AI_RETRY_INITIAL_INTERVAL)
+        .with_max_elapsed_time(Some(AI_RETRY_MAX_ELAPSED))
+        .build();
+    let http_client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config::get().ai_timeout_secs))
+        .build()
+        .wrap_err("building AI HTTP client")?;
+    let client = Client::with_config(OpenAIConfig::new().with_api_key(api_key()?))
+        .with_http_client(http_client)
+        .with_backoff(backoff);
+    Ok(client)
+}
+
+static USAGE: LazyLock<Mutex<TokenUsage>> = LazyLock::new(Default::default);
+
+/// Tokens used by the AI requests of this run, as reported by OpenAI
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokenUsage {
+    pub requests: u32,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+impl TokenUsage {
+    /// In dollars, using prices from the config
+    pub fn cost(&self) -> f64 {
+        let config = config::get();
+        (self.prompt_tokens as f64 * config.input_price_per_million
+            + self.completion_tokens as f64 * config.output_price_per_million)
+            / 1_000_000.0
+    }
+}
+
+/// Totals of all AI requests made so far
+pub fn usage() -> TokenUsage {
+    *USAGE.lock().unwrap()
+}
+
+fn record_usage(response: &CreateChatCompletionResponse) {
+    let mut usage = USAGE.lock().unwrap();
+    usage.requests += 1;
+    if let Some(reported) = &response.usage {
+        usage.prompt_tokens += reported.prompt_tokens;
+        usage.completion_tokens += reported.completion_tokens;
+    }
+    tracing::debug!(
+        "AI usage so far: {} requests, {} prompt + {} completion tokens",
+        usage.requests,
+        usage.prompt_tokens,
+        usage.completion_tokens
+    );
+}
 
-    let mut dish_item_name = HashMap::new();
-    let mut dish_name = HashMap::new();
+/// Sends `request`, each attempt bounded by `ai_timeout_secs` so a stalled answer can't
+/// hang the run
+async fn create_with_timeout(
+    client: &Client<OpenAIConfig>,
+    request: &CreateChatCompletionRequest,
+) -> eyre::Result<CreateChatCompletionResponse> {
+    let body = serde_json::to_string(request)?;
+    if let Some(exchange) = recording::replayed_in_order(AI_RECORDING_KEY, &body)? {
+        let response =
+            serde_json::from_str(&exchange.response).wrap_err("parsing recorded AI response")?;
+        record_usage(&response);
+        return Ok(response);
+    }
+    let timeout = config::get().ai_timeout_secs;
+    let mut attempt = 1;
+    loop {
+        match client.chat().create(request.clone()).await {
+            Ok(response) => {
+                record_usage(&response);
+                recording::record(
+                    AI_RECORDING_KEY,
+                    Some(&body),
+                    200,
+                    &serde_json::to_string(&response)?,
+                )?;
+                return Ok(response);
+            }
+            // the client gives up on an HTTP error at once, it only backs off on API errors
+            Err(OpenAIError::Reqwest(e)) if e.is_timeout() && attempt < TIMEOUT_ATTEMPTS => {
+                tracing::warn!("AI request timed out after {timeout}s, retrying");
+                attempt += 1;
+            }
+            Err(OpenAIError::Reqwest(e)) if e.is_timeout() => {
+                eyre::bail!("AI request timed out after {timeout}s")
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Builds the structured output schema for the AI response, rejecting menus
+/// that would produce an invalid schema (OpenAI answers those with an opaque 400).
+pub fn build_selection_schema(dish_items: &[&DishItem]) -> eyre::Result<serde_json::Value> {
+    if dish_items.is_empty() {
+        eyre::bail!("No dish items to select from");
+    }
 
     let mut properties = serde_json::Map::new();
     for dish_item in dish_items {
-        let dish_item_id = dish_item.id.clone();
-        dish_item_name.insert(dish_item_id.clone(), dish_item.meal_type.name.clone());
-        for dish in &dish_item.options() {
-            dish_name.insert(dish.dish.id.clone(), dish.name.clone());
+        let dish_ids = dish_item
+            .options()
+            .iter()
+            .map(|dish| dish.dish.id.clone())
+            .collect::<Vec<String>>();
+        if dish_ids.is_empty() {
+            eyre::bail!(
+                "Dish item {} ({}) has no enabled options",
+                dish_item.meal_type.name,
+                dish_item.id
+            );
+        }
+        if properties.contains_key(&dish_item.id) {
+            eyre::bail!(
+                "Dish item {} ({}) appears more than once",
+                dish_item.meal_type.name,
+                dish_item.id
+            );
         }
+
         let dish_item_schema = json!({
             "type": "object",
             "properties": {
                 "analysis": {
                     "type": "object",
                     "description": "Analyze available options and argue how good it is for the user",
-                    "properties": dish_item.options().iter().map(|dish| (dish.dish.id.clone(), json!({
+                    "properties": dish_ids.iter().map(|dish_id| (dish_id.clone(), json!({
                         "type": "string",
                     }))).collect::<serde_json::Map<_,_>>(),
-                    "required": dish_item.options().iter().map(|dish| dish.dish.id.clone()).collect::<Vec<String>>(),
+                    "required": dish_ids,
                     "additionalProperties": false
                 },
                 "reason": { "type": "string", "description": "Justification why this meal should fit user preferences" },
-                "dish_id": { "type": "string", "enum": dish_item.options().iter().map(|dish| dish.dish.id.clone()).collect::<Vec<String>>() },
+                "dish_id": { "type": "string", "enum": dish_ids },
+                "comparison": {
+                    "type": "object",
+                    "description": "For every option other than dish_id, why it is less suitable than the selected dish. Empty string for the selected dish",
+                    "properties": dish_ids.iter().map(|dish_id| (dish_id.clone(), json!({
+                        "type": "string",
+                    }))).collect::<serde_json::Map<_,_>>(),
+                    "required": dish_ids,
+                    "additionalProperties": false
+                },
+                "repeat_unavoidable": {
+                    "type": "boolean",
+                    "description": "True only when dish_id was eaten in last_days_choices and every other option was unacceptable to the user"
+                },
             },
-            "required": ["analysis", "reason", "dish_id"],
+            "required": ["analysis", "reason", "dish_id", "comparison", "repeat_unavoidable"],
             "additionalProperties": false
         });
-        properties.insert(dish_item_id, dish_item_schema);
+        properties.insert(dish_item.id.clone(), dish_item_schema);
     }
 
-    let schema = json!({
+    Ok(json!({
         "type": "object",
         "properties": {
             "reasoning": {
@@ -115,9 +414,396 @@ pub async fn select_dish(
         },
         "required": ["reasoning", "selections"],
         "additionalProperties": false
+    }))
+}
+
+/// Selection that keeps the current menu, used in place of the AI when replaying fixtures
+pub fn stub_selection(dish_items: &[DishItem]) -> AiResponse {
+    let selections = dish_items
+        .iter()
+        .filter(|dish_item| !dish_item.is_locked())
+        .filter_map(|dish_item| {
+            let options = dish_item.options();
+            let dish = dish_item
+                .get_selected_option()
+                .filter(|selected| selected.enabled)
+                .or_else(|| options.first().copied())?;
+            Some((
+                dish_item.id.clone(),
+                ResponseItem {
+                    dish_id: dish.dish.id.clone(),
+                    reason: "Stub selection, keeping the current dish".to_string(),
+                    analysis: HashMap::new(),
+                    comparison: HashMap::new(),
+                    repeat_unavoidable: false,
+                },
+            ))
+        })
+        .collect();
+    AiResponse {
+        reasoning: vec!["AI is stubbed, keeping the current menu".to_string()],
+        selections,
+        heuristic: false,
+    }
+}
+
+/// Selection made without the AI, scoring options by the saved preferences, flavor profiles,
+/// rules and recent meals; ties keep the current dish
+pub fn heuristic_selection(
+    date: NaiveDate,
+    dish_items: &[DishItem],
+    last_days_choices: &IndexMap<String, CalendarDayItems>,
+    user_changes: &[UserAdjustment],
+) -> AiResponse {
+    let config = config::get();
+    let recent = last_days_choices
+        .values()
+        .flat_map(|day| day.diet_elements.members.iter())
+        .filter_map(|dish_item| dish_item.get_selected_option())
+        .map(|option| option.name.to_lowercase())
+        .collect::<Vec<_>>();
+    // "no x" flavor profiles and ingredients disliked in override reasons
+    let avoided = config
+        .flavor_profiles
+        .iter()
+        .filter_map(|profile| {
+            profile
+                .trim()
+                .to_lowercase()
+                .strip_prefix("no ")
+                .map(|ingredient| ingredient.trim().to_string())
+        })
+        .chain(
+            tags::tag_frequencies(user_changes)
+                .keys()
+                .filter_map(|tag| {
+                    tag.strip_prefix("disliked_ingredient:")
+                        .map(|ingredient| ingredient.to_lowercase())
+                }),
+        )
+        .filter(|ingredient| !ingredient.is_empty())
+        .collect::<Vec<_>>();
+    let forbidden = config
+        .rules
+        .iter()
+        .map(|rule| rule.check(date, last_days_choices))
+        .filter(|check| check.constraint == Some(Constraint::Forbid))
+        .map(|check| check.rule)
+        .collect::<Vec<_>>();
+
+    let selections = dish_items
+        .iter()
+        .filter(|dish_item| !dish_item.is_locked())
+        .filter_map(|dish_item| {
+            let current = dish_item
+                .get_selected_option()
+                .map(|selected| selected.dish.id.clone());
+            let mut analysis = HashMap::new();
+            let mut best: Option<(f32, &str, String)> = None;
+            for option in dish_item.options() {
+                if let Some(banned) = option.banned(&config.banned_ingredients) {
+                    analysis.insert(option.dish.id.clone(), banned.to_string());
+                    continue;
+                }
+                let name = option.name.to_lowercase();
+                let mut score = 0.0;
+                let mut notes = Vec::new();
+                let chosen = user_changes
+                    .iter()
+                    .filter(|change| change.to.to_lowercase() == name)
+                    .count();
+                if chosen > 0 {
+                    score += 2.0 * chosen as f32;
+                    notes.push(format!("picked over the AI {chosen} time(s)"));
+                }
+                let replaced = user_changes
+                    .iter()
+                    .filter(|change| change.from.to_lowercase() == name)
+                    .count();
+                if replaced > 0 {
+                    score -= 2.0 * replaced as f32;
+                    notes.push(format!("replaced by the user {replaced} time(s)"));
+                }
+                let contents = option
+                    .ingredients
+                    .iter()
+                    .flat_map(|i| i.ingredients.iter().cloned().chain(i.allergens()))
+                    .map(|item| item.to_lowercase())
+                    .chain(std::iter::once(name.clone()))
+                    .collect::<Vec<_>>();
+                if let Some(ingredient) = avoided
+                    .iter()
+                    .find(|avoided| contents.iter().any(|item| item.contains(avoided.as_str())))
+                {
+                    score -= 3.0;
+                    notes.push(format!("contains {ingredient}"));
+                }
+                if !config.allow_repeats && recent.contains(&name) {
+                    score -= 1.0;
+                    notes.push("eaten recently".to_string());
+                }
+                if forbidden.iter().any(|rule| rule.matches(option)) {
+                    score -= 5.0;
+                    notes.push("not allowed by a rule".to_string());
+                }
+                if current.as_ref() == Some(&option.dish.id) {
+                    score += 0.5;
+                }
+                let note = if notes.is_empty() {
+                    "nothing known for or against".to_string()
+                } else {
+                    notes.join(", ")
+                };
+                analysis.insert(option.dish.id.clone(), note.clone());
+                if best
+                    .as_ref()
+                    .is_none_or(|(best_score, _, _)| score > *best_score)
+                {
+                    best = Some((score, option.dish.id.as_str(), note));
+                }
+            }
+            let (_, dish_id, note) = best?;
+            Some((
+                dish_item.id.clone(),
+                ResponseItem {
+                    dish_id: dish_id.to_string(),
+                    reason: format!("Local heuristic: {note}"),
+                    analysis,
+                    comparison: HashMap::new(),
+                    repeat_unavoidable: false,
+                },
+            ))
+        })
+        .collect();
+    AiResponse {
+        reasoning: vec![
+            "Selected without the AI, from saved preferences, flavor profiles, rules and recent meals"
+                .to_string(),
+        ],
+        selections,
+        heuristic: true,
+    }
+}
+
+const PLAN_COMPARISON_PROMPT: &str = "You are personal meal assistant. Compare the user's plan for one day with their usual selections and describe in a few short sentences how the plan deviates, e.g. more or less of some kind of food, or something usually present that is missing. Usual selections count how many times each dish was chosen per meal over the given number of days. Don't list the dishes back, only describe the differences.";
+
+#[derive(Debug, Serialize)]
+pub struct PlanComparison {
+    pub date: NaiveDate,
+    /// meal type -> selected dish
+    pub plan: IndexMap<String, String>,
+    /// meal type -> dish -> times selected
+    pub usual_selections: IndexMap<String, IndexMap<String, usize>>,
+    pub days: usize,
+}
+
+const TRANSLATION_PROMPT: &str = "Translate the Polish dish names to the language the user asks for. Keep each translation short, like a menu entry, and answer with the translations in the same order as the names.";
+
+/// Translations of dish `names` to `language`, in the same order
+pub async fn translate_names(names: &[String], language: &str) -> eyre::Result<Vec<String>> {
+    let config = config::get();
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "translations": {
+                "type": "array",
+                "items": { "type": "string" }
+            }
+        },
+        "required": ["translations"],
+        "additionalProperties": false
     });
+    let request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(2048u32)
+        .model(&config.model)
+        .temperature(0.0)
+        .response_format(ResponseFormat::JsonSchema {
+            json_schema: ResponseFormatJsonSchema {
+                description: None,
+                name: "dish_translations".into(),
+                schema: Some(schema),
+                strict: Some(true),
+            },
+        })
+        .messages([
+            ChatCompletionRequestSystemMessage::from(TRANSLATION_PROMPT).into(),
+            ChatCompletionRequestUserMessage::from(serde_json::to_string(&json!({
+                "language": language,
+                "names": names,
+            }))?)
+            .into(),
+        ])
+        .build()?;
+    let response = create_with_timeout(&client()?, &request).await?;
+    let content = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .ok_or_else(|| eyre::eyre!("No response from AI"))?;
 
-    tracing::info!("Schema: {}", serde_json::to_string_pretty(&schema)?);
+    #[derive(Deserialize)]
+    struct Translations {
+        translations: Vec<String>,
+    }
+    let translations = serde_json::from_str::<Translations>(&content)
+        .wrap_err("parsing translations")?
+        .translations;
+    if translations.len() != names.len() {
+        eyre::bail!(
+            "Got {} translations for {} dish names",
+            translations.len(),
+            names.len()
+        );
+    }
+    Ok(translations)
+}
+
+const NUTRITION_PROMPT: &str = "You are personal meal assistant. Compare each day's nutrition totals with the user's daily goals and point out the days and nutrients that are clearly off. Then suggest specific swaps among the listed options of days that can still be changed, naming the meal, the current dish and the replacement, and how each swap moves the totals. Keep it short and only suggest swaps that help.";
+
+#[derive(Debug, Serialize)]
+pub struct NutritionReview {
+    pub daily_goals: Nutrition,
+    pub days: Vec<NutritionDay>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NutritionDay {
+    pub date: NaiveDate,
+    /// meal type -> selected dish
+    pub selected: IndexMap<String, String>,
+    pub totals: Nutrition,
+    /// meal type -> options, absent when the day can't be changed anymore
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<IndexMap<String, Vec<NutritionOption>>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NutritionOption {
+    pub name: String,
+    pub nutrition: Option<Nutrition>,
+}
+
+/// Where the week misses the nutrition goals and which swaps would help
+pub async fn suggest_nutrition_swaps(review: &NutritionReview) -> eyre::Result<String> {
+    let config = config::get();
+    let request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(1024u32)
+        .model(&config.model)
+        .temperature(config.temperature)
+        .messages([
+            ChatCompletionRequestSystemMessage::from(NUTRITION_PROMPT).into(),
+            ChatCompletionRequestUserMessage::from(serde_json::to_string(review)?).into(),
+        ])
+        .build()?;
+    let response = create_with_timeout(&client()?, &request).await?;
+    response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .ok_or_else(|| eyre::eyre!("No response from AI"))
+}
+
+/// Short description of how `comparison.plan` differs from the usual selections
+pub async fn describe_plan_deviation(comparison: &PlanComparison) -> eyre::Result<String> {
+    let config = config::get();
+    let request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(512u32)
+        .model(&config.model)
+        .temperature(config.temperature)
+        .messages([
+            ChatCompletionRequestSystemMessage::from(PLAN_COMPARISON_PROMPT).into(),
+            ChatCompletionRequestUserMessage::from(serde_json::to_string(comparison)?).into(),
+        ])
+        .build()?;
+    let response = create_with_timeout(&client()?, &request).await?;
+    response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .ok_or_else(|| eyre::eyre!("No response from AI"))
+}
+
+/// Ingredients of dishes selected in the most recent `days` of history
+fn recent_ingredients(
+    last_days_choices: &IndexMap<String, CalendarDayItems>,
+    days: usize,
+) -> Vec<String> {
+    let mut ingredients = Vec::new();
+    for menu in last_days_choices
+        .values()
+        .skip(last_days_choices.len().saturating_sub(days))
+    {
+        for dish in menu
+            .diet_elements
+            .members
+            .iter()
+            .filter_map(|dish_item| dish_item.get_selected_option())
+        {
+            for ingredient in dish.ingredients.iter().flat_map(|i| &i.ingredients) {
+                if !ingredients.contains(ingredient) {
+                    ingredients.push(ingredient.clone());
+                }
+            }
+        }
+    }
+    ingredients
+}
+
+/// Chat completion request asking to select dishes for `date`
+pub fn build_request(
+    date: NaiveDate,
+    dish_items: &[DishItem],
+    last_days_choices: &IndexMap<String, CalendarDayItems>,
+    user_changes: Vec<UserAdjustment>,
+) -> eyre::Result<CreateChatCompletionRequest> {
+    let config = config::get();
+    let rule_checks = config
+        .rules
+        .iter()
+        .map(|rule| rule.check(date, last_days_choices))
+        .collect::<Vec<_>>();
+    let forbidden = rule_checks
+        .iter()
+        .filter(|check| check.constraint == Some(Constraint::Forbid))
+        .map(|check| check.rule)
+        .collect::<Vec<_>>();
+    // locked meals have nothing to choose from, don't waste reasoning on them
+    let dish_items = dish_items
+        .iter()
+        .filter(|dish_item| !dish_item.is_locked())
+        .cloned()
+        .filter_map(|mut dish_item| {
+            if rules::prune(&mut dish_item, &forbidden) {
+                tracing::debug!("Options of {} pruned by rules", dish_item.meal_type.name);
+            }
+            // unlike rules, banned ingredients are never offered even if nothing is left
+            for option in &mut dish_item.options {
+                if option.banned(&config.banned_ingredients).is_some() {
+                    option.enabled = false;
+                }
+            }
+            if dish_item.options().is_empty() {
+                status::notice(&format!(
+                    "\x1b[31mEvery option of {} on {date} may contain a banned ingredient, pick it yourself\x1b[0m",
+                    dish_item.meal_type.name
+                ));
+                return None;
+            }
+            Some(dish_item)
+        })
+        .collect::<Vec<_>>();
+    let dish_items = dish_items.iter().collect::<Vec<_>>();
+    let with_nutrition = dish_items
+        .iter()
+        .flat_map(|dish_item| dish_item.options())
+        .any(|option| option.nutrition().is_some());
+    let schema = build_selection_schema(&dish_items)?;
+    let system_prompt = system_prompt(config, &dish_items);
+
+    tracing::debug!("Schema: {}", serde_json::to_string_pretty(&schema)?);
     let response_format = ResponseFormat::JsonSchema {
         json_schema: ResponseFormatJsonSchema {
             description: None,
@@ -127,69 +813,340 @@ pub async fn select_dish(
         },
     };
 
-    let request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(2048u32)
-        .model("gpt-4o-2024-08-06")
-        .temperature(0.0)
+    let mut request = CreateChatCompletionRequestArgs::default()
+        .max_tokens(config.max_tokens)
+        .model(&config.model)
+        .temperature(config.temperature)
         .messages([
-            ChatCompletionRequestSystemMessage::from(
-                "You are personal meal assistant. You have to select meals for the user. Figure out what the user wants to eat from the menu. Use historic data to figure out user preferences. Try not to pick the same meal as the user had in the last days.",
+            ChatCompletionRequestSystemMessage::from(system_prompt.as_str()).into(),
+            ChatCompletionRequestUserMessage::from(
+                serde_json::to_string(&SelectDishQuestion {
+                    menu_date: date,
+                    daily_nutrition_goals: config.nutrition_goals.filter(|_| with_nutrition),
+                    rules: rule_checks
+                        .into_iter()
+                        .map(|check| check.description)
+                        .collect(),
+                    dish_items: dish_items
+                        .iter()
+                        .map(|dish_item| AiDishItem {
+                            id: dish_item.id.clone(),
+                            meal_type: dish_item.meal_type.name.clone(),
+                            variety_weight: config.variety_weight_for(&dish_item.meal_type.name),
+                            options: dish_item
+                                .options()
+                                .iter()
+                                .map(|dish| AiMenuDietOption {
+                                    name: dish.name.clone(),
+                                    ingredients: dish
+                                        .ingredients
+                                        .as_ref()
+                                        .map(|i| i.ingredients.clone())
+                                        .unwrap_or_default(),
+                                    allergens: dish.allergens(),
+                                    id: dish.dish.id.clone(),
+                                    rating: dish.rating(),
+                                    price: dish.price(),
+                                    surcharge: dish.surcharge(),
+                                    nutrition: dish.nutrition(),
+                                })
+                                .collect(),
+                            current_dish_id: config
+                                .show_current_selection
+                                .then(|| dish_item.get_selected_option())
+                                .flatten()
+                                .map(|dish| dish.dish.id.clone()),
+                        })
+                        .collect(),
+                    preference_tags: tags::tag_frequencies(&user_changes),
+                    variety_weight: config.variety_weight.clamp(0.0, 1.0),
+                    avoid_ingredients: recent_ingredients(
+                        last_days_choices,
+                        config.ingredient_avoidance_days,
+                    ),
+                    cuisine_preferences: config.cuisine_preferences.clone(),
+                    flavor_profiles: config.flavor_profiles.clone(),
+                    user_changes,
+                    last_days_choices: last_days_choices
+                        .iter()
+                        .map(|(day, menu)| {
+                            (
+                                day.clone(),
+                                menu.diet_elements
+                                    .members
+                                    .iter()
+                                    .map(|dish_item| {
+                                        let dish = dish_item
+                                            .get_selected_option()
+                                            .expect("No selected option");
+                                        AiMenuDietOption {
+                                            name: dish.name.clone(),
+                                            ingredients: dish
+                                                .ingredients
+                                                .as_ref()
+                                                .map(|i| i.ingredients.clone())
+                                                .unwrap_or_default(),
+                                            allergens: dish.allergens(),
+                                            id: dish.dish.id.clone(),
+                                            rating: dish.rating(),
+                                            price: dish.price(),
+                                            surcharge: dish.surcharge(),
+                                            nutrition: dish.nutrition(),
+                                        }
+                                    })
+                                    .collect(),
+                            )
+                        })
+                        .collect(),
+                })
+                .unwrap(),
             )
             .into(),
-            ChatCompletionRequestUserMessage::from(serde_json::to_string(&SelectDishQuestion{
-                menu_date: date,
-                dish_items: dish_items.iter().map(|dish_item| AiDishItem {
-                    id: dish_item.id.clone(),
-                    meal_type: dish_item.meal_type.name.clone(),
-                    options: dish_item.options().iter().map(|dish| AiMenuDietOption {
-                        name: dish.name.clone(),
-                        ingredients: dish.ingredients.as_ref().map(|i| i.ingredients.clone()).unwrap_or_default(),
-                        id: dish.dish.id.clone(),
-                    }).collect(),
-                }).collect(),
-                user_changes: Preferences::get_preferences(),
-                last_days_choices: last_days_choices.iter().map(|(day, menu)| {
-                    (day.clone(), menu.diet_elements.members.iter().map(|dish_item| {
-                        let dish = dish_item.get_selected_option().expect("No selected option");
-                        AiMenuDietOption {
-                            name: dish.name.clone(),
-                            ingredients: dish.ingredients.as_ref().map(|i| i.ingredients.clone()).unwrap_or_default(),
-                            id: dish.dish.id.clone(),
-                        }
-                    }).collect())
-                }).collect(),
-            }).unwrap()).into(),
         ])
         .response_format(response_format)
         .build()?;
+    request.seed = config.seed;
+    Ok(request)
+}
+
+/// Rough size and price of a single request, the response is counted at its limit
+#[derive(Debug, Clone, Copy)]
+pub struct UsageEstimate {
+    pub input_tokens: usize,
+    pub max_output_tokens: usize,
+    /// In dollars, using prices from the config
+    pub cost: f64,
+}
+
+pub fn estimate_usage(request: &CreateChatCompletionRequest) -> eyre::Result<UsageEstimate> {
+    // counting the serialized request overestimates a little, fine for a warning
+    let bpe = tiktoken_rs::get_bpe_from_model(&request.model)
+        .or_else(|_| tiktoken_rs::o200k_base())
+        .map_err(|e| eyre::eyre!("loading tokenizer: {e}"))?;
+    let input_tokens = bpe
+        .encode_with_special_tokens(&serde_json::to_string(request)?)
+        .len();
+    let max_output_tokens = request.max_tokens.unwrap_or_default() as usize;
+    let config = config::get();
+    let cost = (input_tokens as f64 * config.input_price_per_million
+        + max_output_tokens as f64 * config.output_price_per_million)
+        / 1_000_000.0;
+    Ok(UsageEstimate {
+        input_tokens,
+        max_output_tokens,
+        cost,
+    })
+}
+
+pub async fn select_dish(
+    date: NaiveDate,
+    dish_items: &[DishItem],
+    last_days_choices: &IndexMap<String, CalendarDayItems>,
+) -> eyre::Result<AiResponse> {
+    select_dish_with_preferences(
+        date,
+        dish_items,
+        last_days_choices,
+        Preferences::get_preferences(),
+    )
+    .await
+}
+
+/// Like `select_dish`, but with the given adjustments instead of the saved ones
+pub async fn select_dish_with_preferences(
+    date: NaiveDate,
+    dish_items: &[DishItem],
+    last_days_choices: &IndexMap<String, CalendarDayItems>,
+    user_changes: Vec<UserAdjustment>,
+) -> eyre::Result<AiResponse> {
+    let config = config::get();
+    if config.no_ai {
+        return Ok(heuristic_selection(
+            date,
+            dish_items,
+            last_days_choices,
+            &user_changes,
+        ));
+    }
+    match request_selection(date, dish_items, last_days_choices, user_changes.clone()).await {
+        // unattended runs save what they get, so they must not save heuristic picks unnoticed
+        Err(e) if config.ai_fallback && config.confirmation_mode != ConfirmationMode::None => {
+            tracing::warn!("AI selection for {date} failed, using the local heuristic: {e:#}");
+            Ok(heuristic_selection(
+                date,
+                dish_items,
+                last_days_choices,
+                &user_changes,
+            ))
+        }
+        result => result,
+    }
+}
+
+async fn request_selection(
+    date: NaiveDate,
+    dish_items: &[DishItem],
+    last_days_choices: &IndexMap<String, CalendarDayItems>,
+    user_changes: Vec<UserAdjustment>,
+) -> eyre::Result<AiResponse> {
+    let client = client()?;
+    let without_ingredients = dish_items
+        .iter()
+        .filter(|dish_item| !dish_item.is_locked())
+        .flat_map(|dish_item| dish_item.options())
+        .filter(|option| option.lacks_ingredients())
+        .map(|option| option.name.as_str())
+        .collect::<Vec<_>>();
+    if !without_ingredients.is_empty() {
+        tracing::warn!(
+            "Selecting for {date} without ingredients of: {}",
+            without_ingredients.join(", ")
+        );
+    }
+    let mut request = build_request(date, dish_items, last_days_choices, user_changes)?;
+
+    let mut attempt = 1;
+    let mut invalid_attempt = 1;
+    loop {
+        let response = create_with_timeout(&client, &request).await?;
+        // changes when OpenAI swaps the backend, explaining different picks with the same seed
+        tracing::debug!(
+            "AI response from {}, system fingerprint {:?}",
+            response.model,
+            response.system_fingerprint
+        );
 
-    let response = client.chat().create(request).await?;
-
-    if let Some(choice) = response.choices.first() {
-        if let Some(content) = &choice.message.content {
-            // println!("{}\n\n\n\n", content);
-            let response: AiResponse = serde_json::from_str(content).wrap_err("in ai response")?;
-            Ok(response)
-            // for reason in &response.reasoning {
-            //     println!("Ai: {}", reason);
-            // }
-            // println!("\n");
-            // for (dish_item_id, dish) in response.selections {
-            //     println!(
-            //         "{}: {}\n   reason: {}",
-            //         dish_item_name
-            //             .get(&dish_item_id)
-            //             .unwrap_or(&"invalid".to_string()),
-            //         dish_name
-            //             .get(&dish.dish_id)
-            //             .unwrap_or(&"invalid".to_string()),
-            //         dish.reason,
-            //     );
-            // }
+        let problem = if let Some(choice) = response.choices.first() {
+            if let Some(content) = &choice.message.content {
+                let e = match serde_json::from_str::<AiResponse>(content) {
+                    Ok(response) => return Ok(response),
+                    Err(e) => e,
+                };
+                if invalid_attempt >= INVALID_RESPONSE_ATTEMPTS {
+                    return Err(e).wrap_err("in ai response");
+                }
+                tracing::warn!(
+                    "Invalid AI response (attempt {invalid_attempt}/{INVALID_RESPONSE_ATTEMPTS}): {e}, retrying"
+                );
+                tracing::debug!("Invalid AI response: {content}");
+                // show the model its answer and what was wrong with it
+                request.messages.push(
+                    ChatCompletionRequestAssistantMessageArgs::default()
+                        .content(content.as_str())
+                        .build()?
+                        .into(),
+                );
+                request.messages.push(
+                    ChatCompletionRequestUserMessage::from(
+                        format!(
+                            "That answer could not be read: {e}. Answer again with JSON matching the schema."
+                        )
+                        .as_str(),
+                    )
+                    .into(),
+                );
+                invalid_attempt += 1;
+                continue;
+            }
+            // content filter or length cutoff, finish reason tells which one
+            format!(
+                "No content in response from AI (finish reason: {:?})",
+                choice.finish_reason
+            )
         } else {
-            eyre::bail!("No content in response from AI");
+            "No response from AI".to_string()
+        };
+
+        if attempt >= EMPTY_RESPONSE_ATTEMPTS {
+            eyre::bail!(problem);
         }
-    } else {
-        eyre::bail!("No response from AI");
+        tracing::warn!("{problem}, retrying");
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Meal offering `options` as (dish id, enabled), the first one selected
+    fn dish_item(id: &str, options: &[(&str, bool)]) -> DishItem {
+        let options = options
+            .iter()
+            .enumerate()
+            .map(|(i, (dish_id, enabled))| {
+                json!({
+                    "name": format!("Danie {i}"),
+                    "enabled": enabled,
+                    "dish": { "@id": dish_id },
+                    "dishSizeId": i,
+                })
+            })
+            .collect::<Vec<_>>();
+        let selected = options[0]["dish"]["@id"].clone();
+        serde_json::from_value(json!({
+            "@id": id,
+            "mealType": { "name": "Obiad" },
+            "dishSize": { "dish": { "@id": selected } },
+            "options": options,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn schema_needs_enabled_options() {
+        assert!(build_selection_schema(&[]).is_err());
+        let disabled = dish_item(
+            "/diet-elements/1",
+            &[("/dishes/1", false), ("/dishes/2", false)],
+        );
+        let error = build_selection_schema(&[&disabled]).unwrap_err();
+        assert!(error.to_string().contains("no enabled options"));
+    }
+
+    #[test]
+    fn schema_rejects_repeated_dish_item_id() {
+        let first = dish_item("/diet-elements/1", &[("/dishes/1", true)]);
+        let again = dish_item("/diet-elements/1", &[("/dishes/2", true)]);
+        let error = build_selection_schema(&[&first, &again]).unwrap_err();
+        assert!(error.to_string().contains("appears more than once"));
+    }
+
+    #[test]
+    fn schema_offers_enabled_dishes_of_each_meal() {
+        let dish_item = dish_item(
+            "/diet-elements/1",
+            &[
+                ("/dishes/1", true),
+                ("/dishes/2", false),
+                ("/dishes/3", true),
+            ],
+        );
+        let schema = build_selection_schema(&[&dish_item]).unwrap();
+        let selection = &schema["properties"]["selections"]["properties"]["/diet-elements/1"];
+        let enabled = json!(["/dishes/1", "/dishes/3"]);
+        assert_eq!(selection["properties"]["dish_id"]["enum"], enabled);
+        assert_eq!(selection["properties"]["analysis"]["required"], enabled);
+        assert_eq!(selection["properties"]["comparison"]["required"], enabled);
+        assert_eq!(
+            selection["properties"]["repeat_unavoidable"]["type"],
+            "boolean"
+        );
+        assert_eq!(
+            selection["required"],
+            json!([
+                "analysis",
+                "reason",
+                "dish_id",
+                "comparison",
+                "repeat_unavoidable"
+            ])
+        );
+        assert_eq!(
+            schema["properties"]["selections"]["required"],
+            json!(["/diet-elements/1"])
+        );
     }
 }
@@ -1,13 +1,102 @@
-use crate::{Calendar, CalendarDayItems, ChangeMenuRequest, DietsList, DishIngredients, DishSizeIngredients, RefreshTokenResponse};
+use crate::{recording, Calendar, CalendarDayItems, ChangeMenuRequest, DietsList, DishIngredients, DishSizeIngredients, RefreshTokenResponse};
 use chrono::{DateTime, Local, NaiveDate};
-use eyre::{Context, Ok};
+use reqwest::StatusCode;
+use std::sync::Mutex;
 
+static LAST_REQUEST: Mutex<Option<String>> = Mutex::new(None);
+
+const CHANGE_MENU_ATTEMPTS: u32 = 3;
+/// Retries of rate limited or server error responses before giving up
+const MAX_RETRIES: u32 = 10;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("rate limited by the API (last status {0})")]
+    RateLimited(StatusCode),
+    #[error("not authorized ({status}): {message}")]
+    Auth { status: StatusCode, message: String },
+    #[error("{context}\nJSON: {body:?}")]
+    Parse {
+        context: &'static str,
+        body: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("in http request")]
+    Http(#[from] reqwest::Error),
+    #[error("server rejected request ({status}): {message}")]
+    Rejected { status: StatusCode, message: String },
+    #[error("menu changed in the meantime ({status}): {message}")]
+    Conflict { status: StatusCode, message: String },
+    #[error("while serializing request")]
+    Serialize(#[source] serde_json::Error),
+    #[error("unexpected response: {0}")]
+    Unexpected(String),
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+/// Method and URL of the most recent API request, for error reports
+pub fn last_request() -> Option<String> {
+    LAST_REQUEST.lock().unwrap().clone()
+}
+
+fn parse<T: serde::de::DeserializeOwned>(data: String, context: &'static str) -> ApiResult<T> {
+    serde_json::from_str(&data).map_err(|source| ApiError::Parse {
+        context,
+        body: data,
+        source,
+    })
+}
+
+/// Longest part of an error response body kept in the error
+const ERROR_BODY_LIMIT: usize = 500;
+
+fn truncate_body(data: &str) -> String {
+    match data.char_indices().nth(ERROR_BODY_LIMIT) {
+        Some((end, _)) => format!("{}...", &data[..end]),
+        None => data.to_string(),
+    }
+}
+
+/// Body of a successful response, any other status is an error
 async fn send_request(
     url: &str,
     token: &str,
     method: reqwest::Method,
     body: Option<String>,
-) -> eyre::Result<String> {
+) -> ApiResult<String> {
+    let (status, data) = send_request_with_status(url, token, method, body).await?;
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        return Err(ApiError::Auth {
+            status,
+            message: truncate_body(&data),
+        });
+    }
+    if !status.is_success() {
+        return Err(ApiError::Rejected {
+            status,
+            message: truncate_body(&data),
+        });
+    }
+    Ok(data)
+}
+
+async fn send_request_with_status(
+    url: &str,
+    token: &str,
+    method: reqwest::Method,
+    body: Option<String>,
+) -> ApiResult<(StatusCode, String)> {
+    let key = format!("{method} {url}");
+    *LAST_REQUEST.lock().unwrap() = Some(key.clone());
+    let recording_error = |e: eyre::Report| ApiError::Unexpected(format!("{e:#}"));
+    if let Some(exchange) = recording::replayed(&key).map_err(recording_error)? {
+        let status = StatusCode::from_u16(exchange.status)
+            .map_err(|_| ApiError::Unexpected(format!("recorded status {}", exchange.status)))?;
+        return Ok((status, exchange.response));
+    }
+    let mut retries = 0;
     loop {
         let client = reqwest::Client::new();
         let request_builder = client
@@ -24,8 +113,12 @@ async fn send_request(
             request_builder
         };
 
-        let response = request_builder.send().await.wrap_err("in http request")?;
-        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS || response.status().is_server_error() {
+        let response = request_builder.send().await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS || response.status().is_server_error() {
+            if retries >= MAX_RETRIES {
+                return Err(ApiError::RateLimited(response.status()));
+            }
+            retries += 1;
             let retry_after = response
                 .headers()
                 .get("Retry-After")
@@ -36,41 +129,56 @@ async fn send_request(
             tracing::warn!("Got {}, retrying", response.status());
             continue;
         }
-        let data = response.text().await.wrap_err("while reading response")?;
-        return Ok(data);
+        let status = response.status();
+        let data = response.text().await?;
+        recording::record(&key, body.as_deref(), status.as_u16(), &data)
+            .map_err(recording_error)?;
+        return Ok((status, data));
     }
 }
 
-pub async fn refresh_token(refresh_token: &str) -> eyre::Result<RefreshTokenResponse> {
+pub async fn refresh_token(refresh_token: &str) -> ApiResult<RefreshTokenResponse> {
     let url = "https://api.powermeal.pl/refresh_token";
     let body = format!("{{\"refreshToken\":\"{refresh_token}\"}}");
     let data = send_request(url, "", reqwest::Method::PUT, Some(body)).await?;
-    let refresh_token_response: RefreshTokenResponse = serde_json::from_str(&data)
-        .wrap_err_with(|| format!("while getting JWT token\nJSON: {data:?}"))?;
-    Ok(refresh_token_response)
+    parse(data, "while getting JWT token")
 }
 
-pub async fn get_diet(
+/// Untouched items endpoint response, useful when the model stops matching the API
+pub async fn get_diet_raw(
     date: &DateTime<Local>,
     diet_id: i64,
     token: &str,
-) -> eyre::Result<CalendarDayItems> {
+) -> ApiResult<String> {
     let url = format!(
         "https://api.powermeal.pl/v2/frontend/secure/calendar/{diet_id}/days/{}/items",
         date.format("%Y-%m-%d"),
     );
-    let data = send_request(&url, token, reqwest::Method::GET, None).await?;
-    let calendar_day_items: CalendarDayItems = serde_json::from_str(&data)
-        .wrap_err_with(|| format!("while parsing json\nJson: {data:?}"))?;
+    send_request(&url, token, reqwest::Method::GET, None).await
+}
+
+pub async fn get_diet(
+    date: &DateTime<Local>,
+    diet_id: i64,
+    token: &str,
+) -> ApiResult<CalendarDayItems> {
+    let data = get_diet_raw(date, diet_id, token).await?;
+    let mut calendar_day_items: CalendarDayItems = parse(data, "while parsing json")?;
+    if let Some(id) = calendar_day_items.duplicate_dish_item_id() {
+        return Err(ApiError::Unexpected(format!(
+            "meal {id} is listed more than once in the menu of {}",
+            date.format("%Y-%m-%d")
+        )));
+    }
+    calendar_day_items.diet_id = Some(diet_id);
+    calendar_day_items.date = Some(date.date_naive());
     Ok(calendar_day_items)
 }
 
-pub async fn fetch_diets(token: &str) -> eyre::Result<DietsList> {
+pub async fn fetch_diets(token: &str) -> ApiResult<DietsList> {
     let url = "https://api.powermeal.pl/frontend/secure/my-diets?pagination=false";
     let data = send_request(url, token, reqwest::Method::GET, None).await?;
-    let diets: DietsList = serde_json::from_str(&data)
-        .wrap_err_with(|| format!("while parsing ordered diets\nJson: {data:?}",))?;
-    Ok(diets)
+    parse(data, "while parsing ordered diets")
 }
 
 pub async fn fetch_calendar(
@@ -78,12 +186,10 @@ pub async fn fetch_calendar(
     diet_id: i64,
     from: NaiveDate,
     to: NaiveDate,
-) -> eyre::Result<Calendar> {
+) -> ApiResult<Calendar> {
     let url = format!("https://api.powermeal.pl/frontend/secure/calendar/{diet_id}/{from}/{to}");
     let data = send_request(&url, token, reqwest::Method::GET, None).await?;
-    let calendar_day_items: Calendar = serde_json::from_str(&data)
-        .wrap_err_with(|| format!("while parsing json\nJson: {data:?}"))?;
-    Ok(calendar_day_items)
+    parse(data, "while parsing json")
 }
 
 pub async fn change_menu(
@@ -91,31 +197,76 @@ pub async fn change_menu(
     date: &NaiveDate,
     diet_id: i64,
     change: &ChangeMenuRequest,
-) -> eyre::Result<()> {
+) -> ApiResult<()> {
     let url = format!(
         "https://api.powermeal.pl/v2/frontend/secure/calendar/{diet_id}/days/{date}/change-menu",
     );
-    let body = serde_json::to_string(change).wrap_err("while serializing items")?;
-    send_request(&url, token, reqwest::Method::PUT, Some(body)).await?;
-    Ok(())
+    let body = serde_json::to_string(change).map_err(ApiError::Serialize)?;
+    let mut attempt = 1;
+    loop {
+        match send_request_with_status(&url, token, reqwest::Method::PUT, Some(body.clone())).await
+        {
+            Ok((status, _)) if status.is_success() => return Ok(()),
+            Ok((status, data))
+                if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN =>
+            {
+                return Err(ApiError::Auth {
+                    status,
+                    message: server_message(&data),
+                });
+            }
+            // the menu doesn't match what the request was based on, e.g. changed in the app
+            Ok((status, data))
+                if status == StatusCode::CONFLICT || status == StatusCode::UNPROCESSABLE_ENTITY =>
+            {
+                return Err(ApiError::Conflict {
+                    status,
+                    message: server_message(&data),
+                });
+            }
+            Ok((status, data)) => {
+                return Err(ApiError::Rejected {
+                    status,
+                    message: server_message(&data),
+                });
+            }
+            Err(ApiError::Http(e)) if attempt < CHANGE_MENU_ATTEMPTS => {
+                tracing::warn!("Saving menu changes failed (attempt {attempt}), retrying: {e}");
+                attempt += 1;
+                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Human readable error from an API error response, falling back to the raw body
+fn server_message(data: &str) -> String {
+    if let Ok(value) = serde_json::from_str::<serde_json::Value>(data) {
+        for key in ["hydra:description", "detail", "message", "hydra:title", "title"] {
+            if let Some(message) = value.get(key).and_then(|v| v.as_str()) {
+                return message.to_string();
+            }
+        }
+    }
+    truncate_body(data)
 }
 
 pub async fn fetch_ingredients(
     token: &str,
     dish_size_id: i64,
-) -> eyre::Result<DishSizeIngredients> {
+) -> ApiResult<DishSizeIngredients> {
     let url = format!(
         "https://api.powermeal.pl/v2/frontend/ingredients_by_dish_sizes/list?dishSizeIds[]={dish_size_id}",
     );
     let data = send_request(&url, token, reqwest::Method::GET, None).await?;
-    let ingredients: DishIngredients = serde_json::from_str(&data)
-        .wrap_err_with(|| format!("while parsing ingredients\nJson: {data:?}",))?;
+    let ingredients: DishIngredients = parse(data, "while parsing ingredients")?;
 
     if ingredients.members.len() != 1 {
-        eyre::bail!(
+        return Err(ApiError::Unexpected(format!(
             "Expected one dish size ingredients, got {}",
             ingredients.members.len()
-        );
+        )));
     }
     Ok(ingredients.members.into_iter().next().unwrap())
 }
@@ -0,0 +1,147 @@
+//! Ingredients by dish size, kept on disk between runs. They rarely change and
+//! fetching them one by one is the slowest part of loading a menu.
+//!
+//! [`Cache`] lists every on-disk cache, to inspect and clear them.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{LazyLock, Mutex},
+};
+
+use eyre::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{preferences, recording, translate, DishSizeIngredients};
+
+const CACHE_FILE: &str = "ingredients_cache.json";
+
+static CACHE: LazyLock<Mutex<HashMap<i64, DishSizeIngredients>>> =
+    LazyLock::new(|| Mutex::new(load()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cache {
+    Ingredients,
+    Translations,
+}
+
+impl Cache {
+    pub const ALL: [Cache; 2] = [Cache::Ingredients, Cache::Translations];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cache::Ingredients => "ingredients",
+            Cache::Translations => "translations",
+        }
+    }
+
+    fn path(self) -> PathBuf {
+        preferences::config_dir().join(match self {
+            Cache::Ingredients => CACHE_FILE,
+            Cache::Translations => translate::TRANSLATIONS_FILE,
+        })
+    }
+
+    /// Number of cached entries and the file size in bytes, None when nothing is cached
+    pub fn info(self) -> eyre::Result<Option<(usize, u64)>> {
+        let path = self.path();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = std::fs::read_to_string(&path)
+            .wrap_err_with(|| format!("reading {}", path.display()))?;
+        let value: serde_json::Value =
+            serde_json::from_str(&text).wrap_err_with(|| format!("parsing {}", path.display()))?;
+        let entries = match self {
+            Cache::Ingredients => value.as_object().map_or(0, |cache| cache.len()),
+            // grouped by language
+            Cache::Translations => value.as_object().map_or(0, |languages| {
+                languages
+                    .values()
+                    .filter_map(|names| names.as_object())
+                    .map(|names| names.len())
+                    .sum()
+            }),
+        };
+        Ok(Some((entries, text.len() as u64)))
+    }
+
+    /// Deletes the cache file, returns whether there was one
+    pub fn clear(self) -> eyre::Result<bool> {
+        if self == Cache::Ingredients {
+            CACHE.lock().unwrap().clear();
+        }
+        let path = self.path();
+        if !path.exists() {
+            return Ok(false);
+        }
+        std::fs::remove_file(&path).wrap_err_with(|| format!("removing {}", path.display()))?;
+        Ok(true)
+    }
+}
+
+pub fn get(dish_size_id: i64) -> Option<DishSizeIngredients> {
+    // recordings must hold every request of the run
+    if recording::active() {
+        return None;
+    }
+    CACHE.lock().unwrap().get(&dish_size_id).cloned()
+}
+
+pub fn contains(dish_size_id: i64) -> bool {
+    if recording::active() {
+        return false;
+    }
+    CACHE.lock().unwrap().contains_key(&dish_size_id)
+}
+
+/// Adds `entries` and writes the cache file once for all of them
+pub fn insert_all(entries: impl IntoIterator<Item = (i64, DishSizeIngredients)>) {
+    if recording::active() {
+        return;
+    }
+    let mut entries = entries.into_iter().peekable();
+    if entries.peek().is_none() {
+        return;
+    }
+    let mut cache = CACHE.lock().unwrap();
+    cache.extend(entries);
+    if let Err(e) = save(&cache) {
+        tracing::warn!("Failed to save ingredients cache: {e:#}");
+    }
+}
+
+fn load() -> HashMap<i64, DishSizeIngredients> {
+    load_json(Cache::Ingredients)
+}
+
+fn save(cache: &HashMap<i64, DishSizeIngredients>) -> eyre::Result<()> {
+    save_json(Cache::Ingredients, cache)
+}
+
+/// Contents of the cache file, empty when there is none or it can't be read
+pub(crate) fn load_json<T: DeserializeOwned + Default>(cache: Cache) -> T {
+    let path = cache.path();
+    if !path.exists() {
+        return T::default();
+    }
+    let contents = std::fs::read_to_string(&path)
+        .wrap_err_with(|| format!("reading {}", path.display()))
+        .and_then(|text| {
+            serde_json::from_str(&text).wrap_err_with(|| format!("parsing {}", path.display()))
+        });
+    contents.unwrap_or_else(|e| {
+        tracing::warn!("Ignoring {} cache: {e:#}", cache.name());
+        T::default()
+    })
+}
+
+pub(crate) fn save_json<T: Serialize>(cache: Cache, contents: &T) -> eyre::Result<()> {
+    let path = cache.path();
+    std::fs::create_dir_all(preferences::config_dir()).wrap_err("creating config directory")?;
+    // written aside and renamed, so a crash or another run never sees half a file
+    let temp = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    std::fs::write(&temp, serde_json::to_string(contents)?)
+        .wrap_err_with(|| format!("writing {}", temp.display()))?;
+    std::fs::rename(&temp, &path).wrap_err_with(|| format!("replacing {}", path.display()))
+}
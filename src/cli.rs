@@ -0,0 +1,320 @@
+use std::path::PathBuf;
+
+use chrono::{Days, Local, Months, NaiveDate};
+use clap::{ArgGroup, Parser, Subcommand};
+use powermeal_ai_choice::config::{Config, ConfirmationMode};
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Select PowerMeal dishes with the help of AI",
+    after_help = "Settings are read from ~/.config/powermeal-ai/config.toml. \
+                  Environment variables override the file and flags override both.\n\n\
+                  Exits with 3 when no day was available to select."
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Show more details about what is happening, like history days without a diet
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+
+    /// Log progress messages instead of updating them in place, implied when output is not a terminal
+    #[arg(long, global = true)]
+    pub no_status: bool,
+
+    /// Save every API and AI request and response of this run to DIR, with tokens redacted
+    #[arg(long, global = true, value_name = "DIR", conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+
+    /// Answer every API and AI request from a recording made with --record, nothing is sent
+    #[arg(long, global = true, value_name = "DIR")]
+    pub replay: Option<PathBuf>,
+
+    /// POST a JSON summary of the run to this URL when it finishes
+    #[arg(long, env = "POWERMEAL_NOTIFY_WEBHOOK")]
+    pub notify_webhook: Option<String>,
+
+    /// OpenAI model used for selection
+    #[arg(long, env = "POWERMEAL_MODEL")]
+    pub model: Option<String>,
+
+    /// Longest answer the model may give when selecting dishes
+    #[arg(long, env = "POWERMEAL_MAX_TOKENS")]
+    pub max_tokens: Option<u32>,
+
+    #[arg(long, env = "POWERMEAL_TEMPERATURE")]
+    pub temperature: Option<f32>,
+
+    /// Seed for repeatable AI picks on the same menu
+    #[arg(long, env = "POWERMEAL_SEED")]
+    pub seed: Option<i64>,
+
+    /// How many previous days of menus are shown to the AI
+    #[arg(long, env = "POWERMEAL_HISTORY_DAYS")]
+    pub history_days: Option<i64>,
+
+    /// 0 favours dishes you are known to like, 1 favours novelty
+    #[arg(long, env = "POWERMEAL_VARIETY_WEIGHT")]
+    pub variety_weight: Option<f32>,
+
+    /// Pick dishes by typing part of the name instead of from a plain list
+    #[arg(long, env = "POWERMEAL_FUZZY_SELECT", num_args = 0..=1, default_missing_value = "true")]
+    pub fuzzy_select: Option<bool>,
+
+    /// Only select days of this diet, instead of finding the diet of each day by its dates
+    #[arg(long, value_name = "ID")]
+    pub diet_id: Option<i64>,
+
+    /// Only select these meal types in this run, e.g. Lunch,Dinner, leaving other meals as they are
+    #[arg(long, value_name = "MEALS", value_delimiter = ',')]
+    pub meals: Vec<String>,
+
+    /// Stop after selecting menus for this many days
+    #[arg(long, value_name = "N")]
+    pub max_days: Option<usize>,
+
+    /// Write the menus of processed days to this iCalendar (.ics) file
+    #[arg(long, value_name = "PATH")]
+    pub export_calendar: Option<PathBuf>,
+
+    /// Select days again even when their menu didn't change since they were selected
+    #[arg(long)]
+    pub force: bool,
+
+    /// Leave days alone when a dish other than the kitchen's default is already selected
+    #[arg(long)]
+    pub untouched_only: bool,
+
+    /// Let the AI plan every selectable day first, then review and save the whole plan at once
+    #[arg(long)]
+    pub plan: bool,
+
+    /// When no day is available, check again every MINUTES until one is
+    #[arg(long, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..))]
+    pub wait: Option<u64>,
+
+    /// Select again days that were already selected and can still be changed, starting from
+    /// their current dishes
+    #[arg(long)]
+    pub reoptimize: bool,
+
+    /// Select dishes with a local heuristic from preferences and history, without the AI
+    #[arg(long)]
+    pub no_ai: bool,
+
+    /// Only print the AI's recommendations to apply in the app, the menu is never changed
+    #[arg(long, conflicts_with = "plan")]
+    pub propose: bool,
+
+    /// Only print what the AI would pick, nothing is asked or saved and the next day to check
+    /// stays where it is
+    #[arg(long, conflicts_with_all = ["plan", "propose"])]
+    pub dry_run: bool,
+
+    /// Accept the AI's picks and save them without asking, for scheduled runs. Fails when the
+    /// AI leaves a meal without a valid pick or the AI request fails
+    #[arg(long, conflicts_with_all = ["propose", "dry_run"])]
+    pub auto: bool,
+
+    /// Fetch ingredients again instead of using the ones cached by earlier runs
+    #[arg(long, global = true)]
+    pub refresh_ingredients: bool,
+}
+
+impl Cli {
+    /// Replaces `config.toml` values with the ones given by flags or environment
+    pub fn apply_overrides(&self, config: &mut Config) {
+        if let Some(notify_webhook) = &self.notify_webhook {
+            config.notify_webhook = Some(notify_webhook.clone());
+        }
+        if let Some(model) = &self.model {
+            config.model = model.clone();
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            config.max_tokens = max_tokens;
+        }
+        if let Some(temperature) = self.temperature {
+            config.temperature = temperature;
+        }
+        if let Some(seed) = self.seed {
+            config.seed = Some(seed);
+        }
+        if let Some(history_days) = self.history_days {
+            config.history_days = history_days;
+        }
+        if let Some(variety_weight) = self.variety_weight {
+            config.variety_weight = variety_weight;
+        }
+        if let Some(fuzzy_select) = self.fuzzy_select {
+            config.fuzzy_select = fuzzy_select;
+        }
+        if self.reoptimize {
+            config.show_current_selection = true;
+        }
+        if self.no_ai {
+            config.no_ai = true;
+        }
+        if self.auto {
+            config.confirmation_mode = ConfirmationMode::None;
+            config.ai_fallback = false;
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Show how often each dish was offered
+    Stats {
+        /// Start of the window: a date (2024-09-01) or a duration back from today (14d, 2w, 1mo)
+        #[arg(long, value_parser = parse_since, default_value = "30d")]
+        since: NaiveDate,
+    },
+    /// Walk through the main settings and the refresh token, also offered on the first run
+    Setup,
+    /// Learn preferences from dishes selected in the past, e.g. in the app
+    Learn {
+        /// Start of the window: a date (2024-09-01) or a duration back from today (14d, 2w, 1mo)
+        #[arg(long, value_parser = parse_since, default_value = "90d")]
+        since: NaiveDate,
+    },
+    /// Ask the AI how a day's selection differs from what you usually pick
+    Compare {
+        /// Day to compare (YYYY-MM-DD), defaults to the latest recorded day
+        date: Option<NaiveDate>,
+        /// Start of the usual pattern: a date (2024-09-01) or a duration back from today (14d, 2w, 1mo)
+        #[arg(long, value_parser = parse_since, default_value = "28d")]
+        since: NaiveDate,
+    },
+    /// Sum up a week's nutrition against nutrition_goals and ask the AI for swaps that help
+    Nutrition {
+        /// Any day of the week (YYYY-MM-DD), defaults to the current week
+        date: Option<NaiveDate>,
+    },
+    /// Show where the AI's picks changed between the two most recent runs
+    DiffRuns,
+    /// Fetch ingredients of upcoming menus and their history ahead of a run
+    WarmCache,
+    /// Never offer a day for selection, e.g. when eating out
+    SkipDate {
+        /// Day to skip (YYYY-MM-DD)
+        date: NaiveDate,
+    },
+    /// Show the dishes chosen for each meal and why
+    Decisions {
+        /// Start of the window: a date (2024-09-01) or a duration back from today (14d, 2w, 1mo)
+        #[arg(long, value_parser = parse_since, default_value = "14d")]
+        since: NaiveDate,
+    },
+    /// Manage learned preferences
+    Prefs {
+        #[command(subcommand)]
+        command: PrefsCommand,
+    },
+    /// Inspect or clear data cached on disk
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Print the untouched menu response for a day
+    #[command(hide = true)]
+    Raw {
+        /// Day of the menu (YYYY-MM-DD)
+        date: NaiveDate,
+    },
+    /// Run the selection against saved API responses instead of the live API
+    #[command(hide = true)]
+    Replay {
+        /// Items endpoint response of the day to select for
+        #[arg(long)]
+        menu: PathBuf,
+        /// Items endpoint responses of previous days, oldest first
+        #[arg(long)]
+        history: Vec<PathBuf>,
+        /// Ingredients endpoint response covering the dishes in the menu and history
+        #[arg(long)]
+        ingredients: Option<PathBuf>,
+        /// Date of the menu, defaults to today
+        #[arg(long)]
+        date: Option<NaiveDate>,
+        /// Don't call OpenAI, keep the current selection for every meal instead
+        #[arg(long)]
+        stub_ai: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+    /// Show the number of entries and the size of each cache
+    Info,
+    /// Delete cached data, it is fetched again when needed
+    #[command(group(ArgGroup::new("caches").required(true).multiple(true)))]
+    Clear {
+        /// Ingredients of dishes
+        #[arg(long, group = "caches")]
+        ingredients: bool,
+        /// Translated dish names
+        #[arg(long, group = "caches")]
+        translations: bool,
+        /// Every cache
+        #[arg(long, group = "caches")]
+        all: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PrefsCommand {
+    /// Show saved preferences with their numbers, oldest first
+    List,
+    /// Remove saved preferences by their numbers from `prefs list`
+    Remove {
+        #[arg(required = true)]
+        numbers: Vec<usize>,
+    },
+    /// Remove preferences added by the last save
+    Undo,
+    /// Report duplicate or contradictory preferences and flavor profiles, without changing anything
+    Validate,
+    /// Show how a preference would change the AI's picks for the next day offering the dish, without saving it
+    Test {
+        /// Dish you would switch away from
+        from: String,
+        /// Dish you would switch to
+        to: String,
+        /// Why, as you would answer when overriding the AI
+        #[arg(long)]
+        reason: Option<String>,
+    },
+}
+
+/// Parses either an absolute date (`YYYY-MM-DD`) or a duration relative to today
+/// (`14d`, `2w`, `1mo`, `1y`) into the first day of the window.
+pub fn parse_since(value: &str) -> Result<NaiveDate, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date);
+    }
+
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in duration {value:?} (use d, w, mo or y)"))?;
+    let (amount, unit) = value.split_at(split);
+    let amount: u32 = amount
+        .parse()
+        .map_err(|_| format!("invalid date or duration {value:?}"))?;
+
+    let today = Local::now().date_naive();
+    let since = match unit {
+        "d" => today.checked_sub_days(Days::new(amount.into())),
+        "w" => today.checked_sub_days(Days::new(u64::from(amount) * 7)),
+        "mo" => today.checked_sub_months(Months::new(amount)),
+        "y" => today.checked_sub_months(Months::new(amount * 12)),
+        _ => {
+            return Err(format!(
+                "unknown duration unit {unit:?} (use d, w, mo or y)"
+            ))
+        }
+    };
+    since.ok_or_else(|| format!("duration {value:?} is out of range"))
+}
@@ -0,0 +1,244 @@
+//! User-tunable settings from `config.toml` in the config dir, kept apart from
+//! what the tool learns (`preferences.json`).
+//!
+//! Precedence, highest first: command line flags, environment variables,
+//! `config.toml`, built-in defaults.
+
+use std::{path::PathBuf, sync::OnceLock};
+
+use chrono::NaiveDate;
+use eyre::Context;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{preferences, rules::Rule, Nutrition, FETCH_HISTORY_DAYS};
+
+const CONFIG_FILE: &str = "config.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// OpenAI model used for selection
+    pub model: String,
+    /// Longest answer the model may give when selecting dishes
+    pub max_tokens: u32,
+    pub temperature: f32,
+    /// Makes the model's answers repeatable on the same menu, as far as OpenAI supports it
+    pub seed: Option<i64>,
+    /// Dollars per million input tokens of `model`, for cost estimates
+    pub input_price_per_million: f64,
+    /// Dollars per million output tokens of `model`
+    pub output_price_per_million: f64,
+    /// How many previous days of menus are shown to the AI
+    pub history_days: i64,
+    /// How history days are labelled for the AI, any of `relative` ("6 days ago"),
+    /// `weekday` ("last Monday") and `date` ("2024-09-02")
+    pub history_labels: Vec<HistoryLabel>,
+    /// 0 favours dishes the user is known to like, 1 favours novelty
+    pub variety_weight: f32,
+    /// Don't ask the AI to avoid dishes eaten in the last days
+    pub allow_repeats: bool,
+    /// `variety_weight` for specific meals, by meal name, e.g. `Śniadanie = 0.1`
+    pub meal_variety_weights: IndexMap<String, f32>,
+    /// How many of the most recent days' ingredients the AI should try to avoid, 0 disables it
+    pub ingredient_avoidance_days: usize,
+    pub objective: Objective,
+    /// Keep a record of offered and selected dishes for `stats`
+    pub record_history: bool,
+    /// Log the dish chosen for each meal with the AI's and your reasons
+    pub record_decisions: bool,
+    /// Show days without an active diet to the AI as empty history entries
+    pub mark_history_gaps: bool,
+    /// Broad taste directions, e.g. "Asian", "Mediterranean"
+    pub cuisine_preferences: Vec<String>,
+    /// Free-form likes and dislikes, e.g. "spicy", "no heavy cream sauces"
+    pub flavor_profiles: Vec<String>,
+    /// Dishes whose name, ingredients or allergens contain any of these are never picked,
+    /// e.g. "orzech" for nut allergies. Matched as parts of words, ignoring case. Dishes with
+    /// unknown ingredients are never picked either while any are set
+    pub banned_ingredients: Vec<String>,
+    /// POST a JSON summary of each run to this URL
+    pub notify_webhook: Option<String>,
+    /// Pick dishes by typing part of the name instead of from a plain list
+    pub fuzzy_select: bool,
+    /// Write the reason for overriding the AI in $EDITOR instead of a single line
+    pub reason_editor: bool,
+    /// When to ask before saving preferences and menu changes
+    pub confirmation_mode: ConfirmationMode,
+    /// Language to show dish names in next to the Polish ones, e.g. "English"
+    pub translate_to: Option<String>,
+    /// Fetch ingredients once more when a dish comes without them, at most once a day per dish
+    pub retry_missing_ingredients: bool,
+    /// Ingredient requests running at once, across all days fetched together
+    pub ingredients_concurrency: usize,
+    /// Previous days fetched at once, each with its own ingredient requests
+    pub history_concurrency: usize,
+    /// Show new preferences as "- from" / "+ to" lines, also used when colors are off
+    pub plain_preference_diff: bool,
+    /// Show the AI the dishes selected now as a starting point to improve on
+    pub show_current_selection: bool,
+    /// Daily nutrition targets, shown to the AI when selecting and used by the `nutrition` command
+    pub nutrition_goals: Option<Nutrition>,
+    /// Limits on how often dishes of some kind are eaten, see `rules::Rule`
+    pub rules: Vec<Rule>,
+    /// Select with a local heuristic from preferences and history, the AI is never asked
+    pub no_ai: bool,
+    /// Fall back to the local heuristic when the AI request fails instead of stopping, ignored
+    /// when `confirmation_mode` is `none`
+    pub ai_fallback: bool,
+    /// How many times to re-apply menu changes on top of changes made elsewhere (e.g. in the app)
+    pub change_menu_conflict_retries: u32,
+    /// Days never offered for selection, as quoted dates (`["2024-12-24"]`)
+    pub exclude_dates: Vec<NaiveDate>,
+    /// Hours before the start of a delivery day when its menu can no longer be changed,
+    /// the API doesn't tell, so no cutoff warnings are shown unless this is set
+    pub order_cutoff_hours: Option<i64>,
+    /// Warn about days whose cutoff is closer than this many hours
+    pub cutoff_warning_hours: i64,
+    /// Seconds to wait for an AI answer before trying again, the second timeout fails the run
+    pub ai_timeout_secs: u64,
+    /// Warn when a meal offers fewer enabled options than this
+    pub min_options: Option<usize>,
+    /// Warn when a meal offers more enabled options than this
+    pub max_options: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            model: "gpt-4o-2024-08-06".to_string(),
+            max_tokens: 2048,
+            temperature: 0.0,
+            seed: None,
+            input_price_per_million: 2.5,
+            output_price_per_million: 10.0,
+            history_days: FETCH_HISTORY_DAYS,
+            history_labels: vec![HistoryLabel::Relative],
+            variety_weight: 0.5,
+            allow_repeats: false,
+            meal_variety_weights: IndexMap::new(),
+            ingredient_avoidance_days: 1,
+            objective: Objective::default(),
+            record_history: true,
+            record_decisions: true,
+            mark_history_gaps: false,
+            cuisine_preferences: Vec::new(),
+            flavor_profiles: Vec::new(),
+            banned_ingredients: Vec::new(),
+            notify_webhook: None,
+            fuzzy_select: false,
+            reason_editor: false,
+            confirmation_mode: ConfirmationMode::default(),
+            translate_to: None,
+            retry_missing_ingredients: true,
+            ingredients_concurrency: 6,
+            history_concurrency: 3,
+            plain_preference_diff: false,
+            show_current_selection: false,
+            nutrition_goals: None,
+            rules: Vec::new(),
+            no_ai: false,
+            ai_fallback: false,
+            exclude_dates: Vec::new(),
+            change_menu_conflict_retries: 1,
+            order_cutoff_hours: None,
+            cutoff_warning_hours: 24,
+            ai_timeout_secs: 120,
+            min_options: None,
+            max_options: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryLabel {
+    Relative,
+    Weekday,
+    Date,
+}
+
+/// What the AI optimizes for besides taste, when the menu carries the data
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Objective {
+    #[default]
+    Taste,
+    TopRated,
+    Cheapest,
+    HighProtein,
+}
+
+/// How often the user confirms what gets saved
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfirmationMode {
+    /// Each preference and each changed meal separately
+    PerMeal,
+    /// Preferences and menu changes once per day
+    #[default]
+    PerDay,
+    /// The whole run at the end, as with `--plan`
+    PerRun,
+    /// Never, everything the AI picks is saved
+    None,
+}
+
+impl Config {
+    /// Variety weight for a meal, falling back to the global one
+    pub fn variety_weight_for(&self, meal_type: &str) -> f32 {
+        self.meal_variety_weights
+            .iter()
+            .find(|(name, _)| name.to_lowercase() == meal_type.to_lowercase())
+            .map(|(_, weight)| *weight)
+            .unwrap_or(self.variety_weight)
+            .clamp(0.0, 1.0)
+    }
+
+    /// Reads `config.toml`, defaults are used when it doesn't exist
+    pub fn load() -> eyre::Result<Self> {
+        let path = config_path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = std::fs::read_to_string(&path)
+            .wrap_err_with(|| format!("reading {}", path.display()))?;
+        let config: Self =
+            toml::from_str(&text).wrap_err_with(|| format!("parsing {}", path.display()))?;
+        if config.ai_timeout_secs == 0 {
+            eyre::bail!("ai_timeout_secs in {} must be at least 1", path.display());
+        }
+        Ok(config)
+    }
+
+    /// Writes all settings to `config.toml`, replacing the file
+    pub fn save(&self) -> eyre::Result<()> {
+        let path = config_path();
+        std::fs::create_dir_all(preferences::config_dir()).wrap_err("creating config directory")?;
+        let text = toml::to_string_pretty(self).wrap_err("serializing config")?;
+        std::fs::write(&path, text).wrap_err_with(|| format!("writing {}", path.display()))
+    }
+}
+
+/// Makes `config` the one returned by `get`, has no effect once `get` was called
+pub fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        tracing::warn!("Config already initialized, overrides ignored");
+    }
+}
+
+/// Settings in effect, loaded from `config.toml` on first use unless `init` was called
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        Config::load().unwrap_or_else(|e| {
+            tracing::warn!("Using default config: {e:#}");
+            Config::default()
+        })
+    })
+}
+
+pub fn config_path() -> PathBuf {
+    preferences::config_dir().join(CONFIG_FILE)
+}
@@ -0,0 +1,108 @@
+//! Append-only log of the dish chosen for each meal and the reasons behind it,
+//! whether the AI's pick was accepted or overridden.
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::{ai::AiResponse, jsonl, CalendarDayItems, ChangeMenuRequest};
+
+const DECISIONS_FILE: &str = "decisions.jsonl";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Decision {
+    pub date: NaiveDate,
+    /// Empty in decisions logged before it was recorded
+    #[serde(default)]
+    pub dish_item: String,
+    pub meal_type: String,
+    pub dish_id: String,
+    pub dish_name: String,
+    /// Whether the AI's pick was kept
+    pub accepted: bool,
+    pub ai_reason: Option<String>,
+    /// Given when overriding the AI
+    pub user_reason: Option<String>,
+    /// Whether the pick offered came from the local heuristic instead of the AI
+    #[serde(default)]
+    pub heuristic: bool,
+    /// Whether the dish ended up in the menu, see [`mark_saved`]. Decisions logged
+    /// before it was recorded count as saved.
+    #[serde(default = "default_saved")]
+    pub saved: bool,
+}
+
+fn default_saved() -> bool {
+    true
+}
+
+/// Decisions for taking every AI pick as is, e.g. when a whole plan is accepted
+pub fn accepted_ai_picks(
+    date: NaiveDate,
+    calendar_day_items: &CalendarDayItems,
+    ai_result: &AiResponse,
+) -> Vec<Decision> {
+    calendar_day_items
+        .diet_elements
+        .members
+        .iter()
+        .filter_map(|dish_item| {
+            let selection = ai_result.selections.get(&dish_item.id)?;
+            let dish = dish_item
+                .options()
+                .into_iter()
+                .find(|option| option.dish.id == selection.dish_id)?;
+            Some(Decision {
+                date,
+                dish_item: dish_item.id.clone(),
+                meal_type: dish_item.meal_type.name.clone(),
+                dish_id: dish.dish.id.clone(),
+                dish_name: dish.name.clone(),
+                accepted: true,
+                ai_reason: Some(selection.reason.clone()),
+                user_reason: None,
+                heuristic: ai_result.heuristic,
+                saved: false,
+            })
+        })
+        .collect()
+}
+
+/// Sets whether each decision's dish ended up in the menu: the one of `saved_changes`
+/// for a changed meal and the current selection of `calendar_day_items` otherwise
+pub fn mark_saved(
+    decisions: &mut [Decision],
+    calendar_day_items: &CalendarDayItems,
+    saved_changes: Option<&ChangeMenuRequest>,
+) {
+    for decision in decisions {
+        let changed = saved_changes.and_then(|changes| {
+            changes
+                .items
+                .iter()
+                .find(|item| item.dish_item == decision.dish_item)
+        });
+        let selected = match changed {
+            Some(item) => Some(item.dish.as_str()),
+            None => calendar_day_items
+                .diet_elements
+                .members
+                .iter()
+                .find(|dish_item| dish_item.id == decision.dish_item)
+                .and_then(|dish_item| dish_item.get_selected_option())
+                .map(|option| option.dish.id.as_str()),
+        };
+        decision.saved = selected == Some(decision.dish_id.as_str());
+    }
+}
+
+pub fn record(decisions: &[Decision]) -> eyre::Result<()> {
+    jsonl::append(DECISIONS_FILE, decisions)
+}
+
+/// Decisions made for days since `since`, oldest first
+pub fn load(since: NaiveDate) -> eyre::Result<Vec<Decision>> {
+    let mut decisions = jsonl::load::<Decision>(DECISIONS_FILE, "decision")?;
+    decisions.retain(|decision| decision.date >= since);
+    decisions.sort_by_key(|decision| decision.date);
+    Ok(decisions)
+}
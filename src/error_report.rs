@@ -0,0 +1,60 @@
+//! Writes details of a failed run to `last_error.log`, so it can be attached
+//! to a bug report without reproducing the failure.
+
+use std::fmt::Write;
+
+use chrono::Local;
+use powermeal_ai_choice::{api, preferences};
+
+const ERROR_LOG_FILE: &str = "last_error.log";
+
+pub fn save(error: &eyre::Report) {
+    let path = preferences::config_dir().join(ERROR_LOG_FILE);
+    let report = redact(&format_report(error));
+    let result = std::fs::create_dir_all(preferences::config_dir())
+        .and_then(|()| std::fs::write(&path, report));
+    match result {
+        Ok(()) => eprintln!("Error details saved to {}", path.display()),
+        Err(e) => tracing::warn!("Failed to save error details: {e}"),
+    }
+}
+
+fn format_report(error: &eyre::Report) -> String {
+    let mut report = String::new();
+    writeln!(report, "time: {}", Local::now().to_rfc3339()).unwrap();
+    writeln!(report, "version: {}", env!("CARGO_PKG_VERSION")).unwrap();
+    writeln!(
+        report,
+        "args: {}",
+        std::env::args().skip(1).collect::<Vec<_>>().join(" ")
+    )
+    .unwrap();
+    if let Some(request) = api::last_request() {
+        writeln!(report, "last request: {request}").unwrap();
+    }
+    writeln!(report).unwrap();
+    writeln!(report, "{error:?}").unwrap();
+    report
+}
+
+/// Masks JWTs (API and refresh tokens) and the stored refresh token
+fn redact(text: &str) -> String {
+    let mut text = text.to_string();
+    if let Some(token) = preferences::Preferences::token().filter(|token| !token.is_empty()) {
+        text = text.replace(&token, "[REDACTED]");
+    }
+
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text.as_str();
+    while let Some(start) = rest.find("eyJ") {
+        redacted.push_str(&rest[..start]);
+        redacted.push_str("[REDACTED]");
+        rest = &rest[start..];
+        let end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+            .unwrap_or(rest.len());
+        rest = &rest[end..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
@@ -0,0 +1,57 @@
+//! Writes selected meals as an iCalendar file, so the plan shows up in calendar apps.
+
+use std::path::Path;
+
+use chrono::NaiveDate;
+use eyre::Context;
+use icalendar::{Calendar, Component, Event, EventLike};
+
+use crate::CalendarDayItems;
+
+#[derive(Debug, Clone)]
+pub struct PlannedMeal {
+    pub date: NaiveDate,
+    pub dish_item: String,
+    pub meal_type: String,
+    pub dish: String,
+}
+
+/// Meals currently selected in `calendar_day_items`
+pub fn planned_meals(date: NaiveDate, calendar_day_items: &CalendarDayItems) -> Vec<PlannedMeal> {
+    calendar_day_items
+        .diet_elements
+        .members
+        .iter()
+        .filter_map(|dish_item| {
+            let dish = dish_item.get_selected_option()?;
+            Some(PlannedMeal {
+                date,
+                dish_item: dish_item.id.clone(),
+                meal_type: dish_item.meal_type.name.clone(),
+                dish: dish.name.clone(),
+            })
+        })
+        .collect()
+}
+
+/// One all-day event per meal, UIDs are stable so importing again updates the events
+pub fn write_calendar(path: &Path, meals: &[PlannedMeal]) -> eyre::Result<()> {
+    let mut calendar = Calendar::new();
+    calendar.name("PowerMeal");
+    for meal in meals {
+        calendar.push(
+            Event::new()
+                .uid(&format!(
+                    "{}-{}@powermeal-ai-choice",
+                    meal.date,
+                    meal.dish_item.rsplit('/').next().unwrap_or_default()
+                ))
+                .summary(&meal.dish)
+                .all_day(meal.date)
+                .add_property("CATEGORIES", &meal.meal_type)
+                .done(),
+        );
+    }
+    std::fs::write(path, calendar.done().to_string())
+        .wrap_err_with(|| format!("writing {}", path.display()))
+}
@@ -0,0 +1,103 @@
+//! Append-only record of offered and selected dishes, kept for analytics
+//! without re-fetching old menus.
+
+use chrono::NaiveDate;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{jsonl, CalendarDayItems, ChangeMenuRequest};
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MenuRecord {
+    pub date: NaiveDate,
+    pub dish_item: String,
+    pub meal_type: String,
+    pub offered: Vec<OfferedDish>,
+    /// dish id that ended up selected
+    pub selected: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OfferedDish {
+    pub id: String,
+    pub name: String,
+}
+
+impl MenuRecord {
+    pub fn selected_name(&self) -> Option<&str> {
+        let selected = self.selected.as_ref()?;
+        self.offered
+            .iter()
+            .find(|dish| &dish.id == selected)
+            .map(|dish| dish.name.as_str())
+    }
+}
+
+/// Appends the day's menu, with `changes` applied on top of the current selection
+pub fn record_day(
+    date: NaiveDate,
+    calendar_day_items: &CalendarDayItems,
+    changes: Option<&ChangeMenuRequest>,
+) -> eyre::Result<()> {
+    let mut records = Vec::new();
+    for dish_item in &calendar_day_items.diet_elements.members {
+        let changed = changes.and_then(|changes| {
+            changes
+                .items
+                .iter()
+                .find(|item| item.dish_item == dish_item.id)
+                .map(|item| item.dish.clone())
+        });
+        let record = MenuRecord {
+            date,
+            dish_item: dish_item.id.clone(),
+            meal_type: dish_item.meal_type.name.clone(),
+            offered: dish_item
+                .options()
+                .iter()
+                .map(|option| OfferedDish {
+                    id: option.dish.id.clone(),
+                    name: option.name.clone(),
+                })
+                .collect(),
+            selected: changed.or_else(|| {
+                dish_item
+                    .get_selected_option()
+                    .map(|option| option.dish.id.clone())
+            }),
+        };
+        records.push(record);
+    }
+    jsonl::append(HISTORY_FILE, &records)
+}
+
+/// How often each dish was selected, by meal type, most frequent first
+pub fn selection_counts(records: &[MenuRecord]) -> IndexMap<String, IndexMap<String, usize>> {
+    let mut counts: IndexMap<String, IndexMap<String, usize>> = IndexMap::new();
+    for record in records {
+        if let Some(name) = record.selected_name() {
+            *counts
+                .entry(record.meal_type.clone())
+                .or_default()
+                .entry(name.to_string())
+                .or_default() += 1;
+        }
+    }
+    for dishes in counts.values_mut() {
+        dishes.sort_by(|_, a, _, b| b.cmp(a));
+    }
+    counts
+}
+
+/// Records since `since`, when a day was recorded more than once the latest record wins
+pub fn load(since: NaiveDate) -> eyre::Result<Vec<MenuRecord>> {
+    let mut records = IndexMap::new();
+    for record in jsonl::load::<MenuRecord>(HISTORY_FILE, "menu history")? {
+        if record.date >= since {
+            records.insert((record.date, record.dish_item.clone()), record);
+        }
+    }
+    Ok(records.into_values().collect())
+}
@@ -0,0 +1,55 @@
+//! Append-only JSON lines files in the config directory, one entry per line.
+//! History, decisions and run plans are all kept this way.
+
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+};
+
+use eyre::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{preferences, recording};
+
+/// Appends `entries` to `file`, nothing is written while replaying a recording
+pub(crate) fn append<T: Serialize>(file: &str, entries: &[T]) -> eyre::Result<()> {
+    if entries.is_empty() || recording::replaying() {
+        return Ok(());
+    }
+    let path = preferences::config_dir().join(file);
+    std::fs::create_dir_all(preferences::config_dir()).wrap_err("creating config directory")?;
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&serde_json::to_string(entry)?);
+        lines.push('\n');
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(lines.as_bytes()))
+        .wrap_err_with(|| format!("writing {}", path.display()))
+}
+
+/// Entries of `file` in the order they were appended, lines that don't parse are
+/// skipped with a warning naming them `what`
+pub(crate) fn load<T: DeserializeOwned>(file: &str, what: &str) -> eyre::Result<Vec<T>> {
+    let path = preferences::config_dir().join(file);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file =
+        std::fs::File::open(&path).wrap_err_with(|| format!("opening {}", path.display()))?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.wrap_err_with(|| format!("reading {}", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => tracing::warn!("Skipping invalid {what} entry: {e}"),
+        }
+    }
+    Ok(entries)
+}
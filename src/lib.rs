@@ -0,0 +1,938 @@
+//! Fetching PowerMeal menus and selecting dishes with AI, without any terminal
+//! interaction. The `powermeal-ai-choice` binary is an interactive CLI over this.
+
+pub mod ai;
+pub mod api;
+pub mod cache;
+pub mod config;
+pub mod decisions;
+pub mod export;
+pub mod history;
+mod jsonl;
+pub mod notify;
+pub mod preferences;
+pub mod recording;
+pub mod rules;
+pub mod runs;
+pub mod serde;
+pub mod status;
+pub mod tags;
+pub mod translate;
+
+use crate::api::*;
+use crate::serde::*;
+use ai::AiResponse;
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use config::HistoryLabel;
+use eyre::Context;
+use indexmap::IndexMap;
+use preferences::Preferences;
+use status::{detail, notice, status};
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock},
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+/// Default of `Config::history_days`
+pub const FETCH_HISTORY_DAYS: i64 = 14;
+
+/// Exchanges the stored refresh token for an API token
+pub async fn authenticate(refresh_token: &str) -> eyre::Result<String> {
+    Ok(api::refresh_token(refresh_token).await?.token)
+}
+
+pub async fn diet_for_date<'a>(
+    token: &str,
+    diet_list: &'a DietsList,
+    date: &DateTime<Local>,
+) -> eyre::Result<Option<&'a Diet>> {
+    if let Some(diet) = diet_list.diet_for_date(date) {
+        return Ok(Some(diet));
+    }
+
+    // In case we reschedule some days in the diet, we might get some days that are outside set delivery dates
+    for diet in diet_list.members.iter() {
+        status(&format!(
+            "Searching for diet: Fetching calendar for diet #{}",
+            diet.id
+        ));
+        let calendar = fetch_calendar(
+            token,
+            diet.id,
+            // Extend the range to fetch the calendar
+            if date < &diet.first_delivery_date {
+                date.date_naive()
+            } else {
+                diet.first_delivery_date.date_naive()
+            },
+            if date > &diet.last_delivery_date {
+                date.date_naive()
+            } else {
+                diet.last_delivery_date.date_naive()
+            },
+        )
+        .await
+        .wrap_err("fetch calendar")?;
+        if let Some(diet_day) = calendar.days.get(&date.date_naive()) {
+            if diet_day.state == DietDayState::AvailableToSelect {
+                return Ok(Some(diet));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Days to select, and the days whose menu isn't published yet
+#[derive(Debug, Default)]
+pub struct SelectableDays {
+    pub days: Vec<DateTime<Local>>,
+    /// Days without a menu yet, to check again in later runs
+    pub without_menu: Vec<NaiveDate>,
+    /// Days that had no menu in an earlier run and can be selected now
+    pub published: Vec<NaiveDate>,
+}
+
+impl SelectableDays {
+    /// Tells which menus are pending or newly published and remembers the pending days
+    /// for the next run. Only for runs that select, other commands look at other ranges.
+    pub fn remember_pending(&self) {
+        for date in &self.without_menu {
+            notice(&format!("{}: Menu pending, will be selectable later", date));
+        }
+        for date in &self.published {
+            notice(&format!("{}: Menu published, now selectable", date));
+        }
+        Preferences::set_pending_menu_dates(self.without_menu.clone());
+    }
+}
+
+pub async fn days_available_to_select(
+    token: &str,
+    diets: &DietsList,
+) -> eyre::Result<SelectableDays> {
+    let next_day = preferences::Preferences::next_day_to_check().unwrap_or_else(chrono::Local::now);
+    days_available_to_select_from(token, diets, next_day).await
+}
+
+/// Like [`days_available_to_select`], but starting at `next_day` instead of
+/// the day after the last selected one, to select days again
+pub async fn days_available_to_select_from(
+    token: &str,
+    diets: &DietsList,
+    next_day: DateTime<Local>,
+) -> eyre::Result<SelectableDays> {
+    let mut days = Vec::new();
+    let end_day = next_day + chrono::Duration::days(14);
+    // Days that had no menu yet are checked again even if later days were already selected
+    let pending = Preferences::pending_menu_dates();
+    let start_day = pending
+        .iter()
+        .map(|date| Local.from_local_datetime(&(*date).into()).unwrap())
+        .chain([next_day])
+        .min()
+        .unwrap();
+
+    #[derive(Debug, PartialEq)]
+    enum DietDayStatus {
+        AvailableToSelect,
+        NotBoughtDiet,
+        WithoutMenu,
+        Other,
+    }
+    let mut diet_day_status: HashMap<NaiveDate, DietDayStatus> = HashMap::new();
+
+    for diet in diets.diets_in_time_range(&start_day, &end_day) {
+        status(&format!("Fetching calendar for diet #{}", diet.id));
+        let calendar = fetch_calendar(token, diet.id, start_day.date_naive(), end_day.date_naive())
+            .await
+            .wrap_err("fetching calendar")?;
+        for (date, status) in calendar.days {
+            if date < next_day.date_naive() && !pending.contains(&date) {
+                continue;
+            }
+            if diets.is_delivery_gap(date) {
+                continue;
+            }
+            if status.state == DietDayState::AvailableToSelect {
+                diet_day_status.insert(date, DietDayStatus::AvailableToSelect);
+                days.push(Local.from_local_datetime(&date.into()).unwrap());
+            } else if status.state == DietDayState::NotBoughtDiet {
+                diet_day_status
+                    .entry(date)
+                    .or_insert(DietDayStatus::NotBoughtDiet);
+            } else if status.state == DietDayState::WithoutMenu {
+                if diet_day_status.get(&date) != Some(&DietDayStatus::AvailableToSelect) {
+                    diet_day_status.insert(date, DietDayStatus::WithoutMenu);
+                }
+            } else if matches!(
+                diet_day_status.get(&date),
+                None | Some(DietDayStatus::NotBoughtDiet)
+            ) {
+                diet_day_status.insert(date, DietDayStatus::Other);
+            }
+        }
+    }
+
+    let mut without_menu = Vec::new();
+    let mut published = Vec::new();
+    for (date, status) in diet_day_status {
+        match status {
+            DietDayStatus::NotBoughtDiet => notice(&format!("{}: No diet bought", date)),
+            DietDayStatus::WithoutMenu => without_menu.push(date),
+            DietDayStatus::AvailableToSelect if pending.contains(&date) => published.push(date),
+            _ => {}
+        }
+    }
+    without_menu.sort_unstable();
+    published.sort_unstable();
+
+    let mut excluded = Preferences::excluded_dates();
+    excluded.extend(&config::get().exclude_dates);
+    days.retain(|day| {
+        let skip = excluded.contains(&day.date_naive());
+        if skip {
+            detail(&format!("{}: Excluded, skipping", day.date_naive()));
+        }
+        !skip
+    });
+
+    days.sort_unstable();
+    Ok(SelectableDays {
+        days,
+        without_menu,
+        published,
+    })
+}
+
+/// FNV-1a hash of `text`, unlike DefaultHasher it's stable across Rust releases
+pub fn stable_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Hours left to change the menu of `day`, None when `order_cutoff_hours` isn't configured
+pub fn hours_until_cutoff(day: &DateTime<Local>) -> Option<i64> {
+    let lead_hours = config::get().order_cutoff_hours?;
+    let day_start = Local
+        .from_local_datetime(&day.date_naive().into())
+        .earliest()?;
+    let cutoff = day_start - chrono::Duration::hours(lead_hours);
+    Some((cutoff - Local::now()).num_hours())
+}
+
+/// Meals whose number of options is outside `min_options`..=`max_options`,
+/// which usually means a menu data issue or a day worth reviewing by hand
+pub fn unusual_option_counts(calendar_day_items: &CalendarDayItems) -> Vec<String> {
+    let config = config::get();
+    calendar_day_items
+        .diet_elements
+        .members
+        .iter()
+        .filter(|dish_item| !dish_item.locked)
+        .filter_map(|dish_item| {
+            let count = dish_item.options().len();
+            if config.min_options.is_some_and(|min| count < min) {
+                Some(format!(
+                    "{} has only {count} options",
+                    dish_item.display_name()
+                ))
+            } else if config.max_options.is_some_and(|max| count > max) {
+                Some(format!("{} has {count} options", dish_item.display_name()))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+pub async fn get_diet_with_ingredients(
+    date: &DateTime<Local>,
+    diet_id: i64,
+    token: &str,
+) -> eyre::Result<CalendarDayItems> {
+    let mut calendar_day_items = get_diet(date, diet_id, token).await?;
+    let mut missing = Vec::new();
+    for option in calendar_day_items.options_mut() {
+        if option.ingredients.is_none() {
+            option.ingredients = cache::get(option.dish_size_id);
+        }
+        if option.ingredients.is_none() && !missing.contains(&option.dish_size_id) {
+            missing.push(option.dish_size_id);
+        }
+    }
+    if !missing.is_empty() {
+        status(&format!("Fetching ingredients of {} dishes", missing.len()));
+    }
+    let fetched = fetch_and_cache_ingredients(token, missing).await?;
+
+    // the AI reasons much worse about a dish without ingredients
+    let mut lacking = Vec::new();
+    let retry_after = chrono::Duration::hours(MISSING_INGREDIENTS_RETRY_HOURS);
+    for option in calendar_day_items.options_mut() {
+        if option.ingredients.is_none() {
+            option.ingredients = fetched.get(&option.dish_size_id).cloned();
+        }
+        // entries cached before fetches were timestamped are due right away
+        let retry_due = option
+            .ingredients
+            .as_ref()
+            .and_then(|i| i.fetched_at)
+            .is_none_or(|at| Local::now() - at >= retry_after);
+        if option.lacks_ingredients()
+            && retry_due
+            && config::get().retry_missing_ingredients
+            && !lacking.contains(&option.dish_size_id)
+        {
+            lacking.push(option.dish_size_id);
+        }
+    }
+    if lacking.is_empty() {
+        return Ok(calendar_day_items);
+    }
+    status(&format!(
+        "Fetching missing ingredients of {} dishes",
+        lacking.len()
+    ));
+    let mut refetched = HashMap::new();
+    for (dish_size_id, ingredients) in fetch_ingredients_concurrently(token, lacking).await? {
+        match ingredients {
+            // still empty ones are cached too, their timestamp postpones the next retry
+            Ok(ingredients) => {
+                refetched.insert(dish_size_id, ingredients);
+            }
+            Err(e) => tracing::warn!(
+                "Fetching ingredients of dish size {dish_size_id} again failed: {e:#}"
+            ),
+        }
+    }
+    for option in calendar_day_items.options_mut() {
+        if let Some(ingredients) = refetched.get(&option.dish_size_id) {
+            option.ingredients = Some(ingredients.clone());
+        }
+    }
+    cache::insert_all(refetched);
+    Ok(calendar_day_items)
+}
+
+/// Dishes listed without ingredients are fetched again at most this often
+const MISSING_INGREDIENTS_RETRY_HOURS: i64 = 24;
+
+/// Shared by every day fetched at once, so fetching history doesn't multiply the requests
+static INGREDIENT_PERMITS: LazyLock<Semaphore> =
+    LazyLock::new(|| Semaphore::new(config::get().ingredients_concurrency.max(1)));
+
+/// Fetches ingredients of `dish_size_ids` a few at a time, see
+/// `Config::ingredients_concurrency`. Rate limits are retried per request.
+async fn fetch_ingredients_concurrently(
+    token: &str,
+    dish_size_ids: Vec<i64>,
+) -> eyre::Result<Vec<(i64, ApiResult<DishSizeIngredients>)>> {
+    let total = dish_size_ids.len();
+    let mut tasks = JoinSet::new();
+    for dish_size_id in dish_size_ids {
+        let token = token.to_string();
+        tasks.spawn(status::inherit_quiet(async move {
+            let _permit = INGREDIENT_PERMITS.acquire().await?;
+            let ingredients = fetch_ingredients(&token, dish_size_id).await;
+            let ingredients = ingredients.map(|ingredients| DishSizeIngredients {
+                fetched_at: Some(Local::now()),
+                ..ingredients
+            });
+            eyre::Ok((dish_size_id, ingredients))
+        }));
+    }
+    let mut results = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        results.push(result.wrap_err("fetching ingredients")??);
+        status(&format!("Fetched ingredients {}/{total}", results.len()));
+    }
+    Ok(results)
+}
+
+/// Fetches ingredients of `dish_size_ids` and caches them. What was fetched is cached
+/// even when another dish failed, the first failure is returned after that.
+async fn fetch_and_cache_ingredients(
+    token: &str,
+    dish_size_ids: Vec<i64>,
+) -> eyre::Result<HashMap<i64, DishSizeIngredients>> {
+    let mut fetched = HashMap::new();
+    let mut failed: eyre::Result<()> = Ok(());
+    for (dish_size_id, ingredients) in fetch_ingredients_concurrently(token, dish_size_ids).await? {
+        match ingredients {
+            Ok(ingredients) => {
+                fetched.insert(dish_size_id, ingredients);
+            }
+            Err(e) if failed.is_ok() => {
+                failed = Err(e)
+                    .wrap_err_with(|| format!("fetching ingredients of dish size {dish_size_id}"))
+            }
+            Err(_) => {}
+        }
+    }
+    cache::insert_all(fetched.clone());
+    failed.map(|()| fetched)
+}
+
+/// Fetches ingredients of every dish offered on `days` that are not cached yet,
+/// a few at a time, returns how many were fetched
+pub async fn warm_ingredients_cache(
+    token: &str,
+    diets: &DietsList,
+    days: &[DateTime<Local>],
+) -> eyre::Result<usize> {
+    let mut missing = Vec::new();
+    for date in days {
+        status(&format!("Fetching menu for {}...", date.format("%Y-%m-%d")));
+        let Some(diet) = diet_for_date(token, diets, date)
+            .await
+            .wrap_err_with(|| format!("find diet day for {date}"))?
+        else {
+            continue;
+        };
+        let calendar_day_items = get_diet(date, diet.id, token)
+            .await
+            .wrap_err("getting diet")?;
+        for dish_item in &calendar_day_items.diet_elements.members {
+            for option in &dish_item.options {
+                if !cache::contains(option.dish_size_id) && !missing.contains(&option.dish_size_id)
+                {
+                    missing.push(option.dish_size_id);
+                }
+            }
+        }
+    }
+
+    Ok(fetch_and_cache_ingredients(token, missing).await?.len())
+}
+
+pub async fn fetch_historical_orders(
+    token: &str,
+    diets: &DietsList,
+    date: &DateTime<Local>,
+    days: i64,
+    run_selections: &HashMap<NaiveDate, ChangeMenuRequest>,
+) -> eyre::Result<IndexMap<String, CalendarDayItems>> {
+    let diets = Arc::new(diets.clone());
+    let permits = Arc::new(Semaphore::new(config::get().history_concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for day in (1..=days).rev() {
+        let date = date
+            .checked_sub_signed(chrono::Duration::days(day))
+            .unwrap();
+        if diets.is_delivery_gap(date.date_naive()) {
+            detail(&format!("No delivery on {}", date.format("%Y-%m-%d %A")));
+            continue;
+        }
+        let token = token.to_string();
+        let diets = diets.clone();
+        let permits = permits.clone();
+        tasks.spawn(status::inherit_quiet(async move {
+            let _permit = permits.acquire_owned().await?;
+            let Some(diet) = diet_for_date(&token, &diets, &date)
+                .await
+                .wrap_err_with(|| format!("find diet day for {date}"))?
+            else {
+                return Ok((day, date, None));
+            };
+            let calendar_day_items = get_diet_with_ingredients(&date, diet.id, &token).await?;
+            eyre::Ok((day, date, Some(calendar_day_items)))
+        }));
+    }
+    let total = tasks.len();
+    let mut fetched = Vec::new();
+    status(&format!("Fetching menus of {total} previous days"));
+    while let Some(result) = tasks.join_next().await {
+        fetched.push(result.wrap_err("fetching history")??);
+        status(&format!(
+            "Fetched menus of {}/{total} previous days",
+            fetched.len()
+        ));
+    }
+    // labels are told to the AI oldest day first, whatever order the fetches finished in
+    fetched.sort_by_key(|(day, _, _)| std::cmp::Reverse(*day));
+
+    let mut last_days_choices = IndexMap::new();
+    for (day, date, calendar_day_items) in fetched {
+        if let Some(mut calendar_day_items) = calendar_day_items {
+            if let Some(changes) = run_selections.get(&date.date_naive()) {
+                calendar_day_items.apply_changes(changes);
+            }
+            insert_history_day(
+                &mut last_days_choices,
+                history_label(date.date_naive(), day),
+                date.date_naive(),
+                calendar_day_items,
+            );
+        } else {
+            detail(&format!("No diet active for {}", date.format("%Y-%m-%d")));
+            if config::get().mark_history_gaps {
+                // an empty day tells the AI there was a gap rather than hiding it
+                insert_history_day(
+                    &mut last_days_choices,
+                    format!(
+                        "{} (no diet, skipped)",
+                        history_label(date.date_naive(), day)
+                    ),
+                    date.date_naive(),
+                    CalendarDayItems {
+                        diet_elements: DietElements {
+                            members: Vec::new(),
+                        },
+                        extra: serde_json::Map::new(),
+                        diet_id: None,
+                        date: Some(date.date_naive()),
+                    },
+                );
+            }
+        }
+    }
+    Ok(last_days_choices)
+}
+
+/// Key of a history day shown to the AI, made of the parts in `history_labels`
+pub fn history_label(date: NaiveDate, days_ago: i64) -> String {
+    let mut parts = Vec::new();
+    for part in &config::get().history_labels {
+        parts.push(match part {
+            HistoryLabel::Relative if days_ago == 1 => "yesterday".to_string(),
+            HistoryLabel::Relative => format!("{days_ago} days ago"),
+            HistoryLabel::Weekday if days_ago <= 7 => format!("last {}", date.format("%A")),
+            HistoryLabel::Weekday => date.format("%A").to_string(),
+            HistoryLabel::Date => date.to_string(),
+        });
+    }
+    if parts.is_empty() {
+        // keys must stay unique, fall back to the date
+        return date.to_string();
+    }
+    parts.join(", ")
+}
+
+/// Adds a history day under `label`, with the date appended when an earlier day has the
+/// same label (e.g. two Mondays with only `weekday` labels), so it doesn't replace that day
+pub fn insert_history_day(
+    days: &mut IndexMap<String, CalendarDayItems>,
+    label: String,
+    date: NaiveDate,
+    calendar_day_items: CalendarDayItems,
+) {
+    let label = if days.contains_key(&label) {
+        format!("{label}, {date}")
+    } else {
+        label
+    };
+    days.insert(label, calendar_day_items);
+}
+
+/// Adjustments for meals where a dish other than the kitchen's default was
+/// selected, to learn from days selected without this tool
+pub fn adjustments_from_selections(
+    date: NaiveDate,
+    calendar_day_items: &CalendarDayItems,
+) -> Vec<ai::UserAdjustment> {
+    calendar_day_items
+        .diet_elements
+        .members
+        .iter()
+        .filter(|dish_item| !dish_item.locked)
+        .filter_map(|dish_item| {
+            let default = dish_item.default_option()?;
+            let selected = dish_item.get_selected_option()?;
+            (selected.dish.id != default.dish.id).then(|| ai::UserAdjustment {
+                from: default.name.clone(),
+                to: selected.name.clone(),
+                reason: None,
+                date,
+                tags: Vec::new(),
+            })
+        })
+        .collect()
+}
+
+/// Menu of a day, fetched with ingredients
+#[derive(Debug)]
+pub struct DayMenu {
+    pub date: DateTime<Local>,
+    pub diet_id: i64,
+    pub calendar_day_items: CalendarDayItems,
+    /// Meals of the diet absent from the menu
+    pub missing_meals: Vec<String>,
+}
+
+/// Menu of a day with the AI's picks, for frontends other than the interactive one
+#[derive(Debug)]
+pub struct DayPlan {
+    pub menu: DayMenu,
+    /// None when all meals are locked
+    pub ai: Option<AiResponse>,
+    /// Menu changes that apply the AI's picks, submit with `change_menu`
+    pub changes: ChangeMenuRequest,
+}
+
+/// Menu of the diet delivered on `date`
+pub async fn fetch_day_menu(
+    token: &str,
+    date: &DateTime<Local>,
+    diets: &DietsList,
+) -> eyre::Result<DayMenu> {
+    let diet = diet_for_date(token, diets, date)
+        .await
+        .wrap_err_with(|| format!("find diet day for {date}"))?
+        .ok_or_else(|| eyre::eyre!("no diet for date {date}"))?;
+    let calendar_day_items = get_diet_with_ingredients(date, diet.id, token)
+        .await
+        .wrap_err("getting diet with ingredients")?;
+    Ok(DayMenu {
+        date: *date,
+        diet_id: diet.id,
+        missing_meals: diet.missing_meal_types(&calendar_day_items),
+        calendar_day_items,
+    })
+}
+
+/// The AI's picks for the unlocked meals of `calendar_day_items`. `run_selections`
+/// are changes to earlier days not saved yet, shown to the AI in history as if they were.
+pub async fn select_with_ai(
+    token: &str,
+    date: &DateTime<Local>,
+    diets: &DietsList,
+    calendar_day_items: &CalendarDayItems,
+    run_selections: &HashMap<NaiveDate, ChangeMenuRequest>,
+    user_changes: Vec<ai::UserAdjustment>,
+) -> eyre::Result<AiResponse> {
+    let last_days_choices = fetch_historical_orders(
+        token,
+        diets,
+        date,
+        config::get().history_days,
+        run_selections,
+    )
+    .await
+    .wrap_err("fetching historical orders")?;
+    status("Ai is thinking...");
+    ai::select_dish_with_preferences(
+        date.date_naive(),
+        &calendar_day_items.diet_elements.members,
+        &last_days_choices,
+        user_changes,
+    )
+    .await
+    .wrap_err("selecting dish with ai")
+}
+
+/// Fetches the menu of `date` and the AI's picks for it with the saved preferences,
+/// nothing is asked or changed
+pub async fn plan_day(
+    token: &str,
+    date: &DateTime<Local>,
+    diets: &DietsList,
+) -> eyre::Result<DayPlan> {
+    let menu = fetch_day_menu(token, date, diets).await?;
+    if menu.calendar_day_items.all_locked() {
+        return Ok(DayPlan {
+            menu,
+            ai: None,
+            changes: ChangeMenuRequest::default(),
+        });
+    }
+    let ai = select_with_ai(
+        token,
+        date,
+        diets,
+        &menu.calendar_day_items,
+        &HashMap::new(),
+        Preferences::get_preferences(),
+    )
+    .await?;
+    let changes = ai_menu_changes(&menu.calendar_day_items, &ai);
+    Ok(DayPlan {
+        menu,
+        ai: Some(ai),
+        changes,
+    })
+}
+
+/// Menu changes needed to switch the current menu to the AI's picks
+pub fn ai_menu_changes(
+    calendar_day_items: &CalendarDayItems,
+    ai_result: &AiResponse,
+) -> ChangeMenuRequest {
+    ai_menu_changes_with_banned(
+        calendar_day_items,
+        ai_result,
+        &config::get().banned_ingredients,
+    )
+}
+
+/// Like `ai_menu_changes`, but with the given banned ingredients instead of the configured ones
+pub fn ai_menu_changes_with_banned(
+    calendar_day_items: &CalendarDayItems,
+    ai_result: &AiResponse,
+    banned: &[String],
+) -> ChangeMenuRequest {
+    let mut menu_changes = ChangeMenuRequest::default();
+    for dish_item in &calendar_day_items.diet_elements.members {
+        let Some(selection) = ai_result.selections.get(&dish_item.id) else {
+            continue;
+        };
+        if !dish_item
+            .options()
+            .iter()
+            .any(|option| option.dish.id == selection.dish_id)
+        {
+            tracing::warn!(
+                "AI picked unavailable dish {} for {}, keeping the current one",
+                selection.dish_id,
+                dish_item.meal_type.name
+            );
+            continue;
+        }
+        if let Some(banned) = dish_item
+            .get_dish(&selection.dish_id)
+            .and_then(|option| option.banned(banned))
+        {
+            tracing::warn!(
+                "AI picked dish {} for {}, but it {banned}, keeping the current one",
+                selection.dish_id,
+                dish_item.meal_type.name
+            );
+            continue;
+        }
+        let current = dish_item.get_selected_option().map(|x| x.dish.id.as_str());
+        if current != Some(selection.dish_id.as_str()) {
+            menu_changes.items.push(ChangeMenuItem {
+                dish: selection.dish_id.clone(),
+                dish_item: dish_item.id.clone(),
+            });
+        }
+    }
+    menu_changes
+}
+
+/// Position of the AI's pick among the options of `dish_item`, None when it picked
+/// nothing, a dish that isn't offered, or one not known to be free of `banned`
+pub fn ai_pick_position(
+    dish_item: &DishItem,
+    ai_result: &AiResponse,
+    banned: &[String],
+) -> Option<usize> {
+    let selection = ai_result.selections.get(&dish_item.id)?;
+    dish_item
+        .option_position(&selection.dish_id)
+        .filter(|i| dish_item.options()[*i].banned(banned).is_none())
+}
+
+/// Changes still needed to reach the `intended` selection on a freshly fetched menu
+pub fn reconcile_menu_changes(
+    calendar_day_items: &CalendarDayItems,
+    intended: &ChangeMenuRequest,
+) -> eyre::Result<ChangeMenuRequest> {
+    let mut menu_changes = ChangeMenuRequest::default();
+    for item in &intended.items {
+        let dish_item = calendar_day_items
+            .get_dish_item(&item.dish_item)
+            .ok_or_else(|| eyre::eyre!("meal {} is no longer on the menu", item.dish_item))?;
+        if dish_item
+            .get_selected_option()
+            .is_some_and(|option| option.dish.id == item.dish)
+        {
+            continue;
+        }
+        if dish_item.locked
+            || !dish_item
+                .options()
+                .iter()
+                .any(|option| option.dish.id == item.dish)
+        {
+            eyre::bail!(
+                "{} can no longer be changed to the selected dish",
+                dish_item.meal_type.name
+            );
+        }
+        menu_changes.items.push(item.clone());
+    }
+    Ok(menu_changes)
+}
+
+/// Fails when `date` can't be changed anymore, e.g. its cutoff passed after the day was listed
+pub async fn ensure_available_to_select(
+    token: &str,
+    date: NaiveDate,
+    diet_id: i64,
+) -> eyre::Result<()> {
+    let calendar = fetch_calendar(token, diet_id, date, date)
+        .await
+        .wrap_err("fetching calendar")?;
+    match calendar.days.get(&date) {
+        Some(day) if day.state == DietDayState::AvailableToSelect => Ok(()),
+        Some(day) => eyre::bail!(
+            "Menu for {date} can no longer be changed ({:?}), its cutoff has probably passed",
+            day.state
+        ),
+        None => eyre::bail!("{date} is no longer in the calendar of diet #{diet_id}"),
+    }
+}
+
+/// Saves `menu_changes`. When the menu was changed elsewhere in the meantime,
+/// they are re-applied on top of the current menu, up to the configured number of times.
+pub async fn save_menu_changes(
+    token: &str,
+    date: NaiveDate,
+    diet_id: i64,
+    menu_changes: &ChangeMenuRequest,
+) -> eyre::Result<()> {
+    // the day may have aged out while the user was choosing
+    ensure_available_to_select(token, date, diet_id).await?;
+    let mut pending = menu_changes.clone();
+    let mut conflicts = 0;
+    loop {
+        match change_menu(token, &date, diet_id, &pending).await {
+            Ok(()) => return Ok(()),
+            Err(ApiError::Conflict { status, message })
+                if conflicts < config::get().change_menu_conflict_retries =>
+            {
+                conflicts += 1;
+                notice(&format!(
+                    "Menu for {date} was changed elsewhere ({status}: {message}), reconciling"
+                ));
+                let datetime = Local.from_local_datetime(&date.into()).unwrap();
+                let current = get_diet(&datetime, diet_id, token)
+                    .await
+                    .wrap_err("fetching current menu")?;
+                pending = reconcile_menu_changes(&current, menu_changes)
+                    .wrap_err("menu changes conflict with changes made elsewhere")?;
+                if pending.items.is_empty() {
+                    return Ok(());
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Meal offering `options` as (dish id, name, enabled), the first one selected
+    fn meal(id: &str, options: &[(&str, &str, bool)]) -> serde_json::Value {
+        let options = options
+            .iter()
+            .enumerate()
+            .map(|(i, (dish_id, name, enabled))| {
+                json!({
+                    "name": name,
+                    "enabled": enabled,
+                    "dish": { "@id": dish_id },
+                    "dishSizeId": i,
+                })
+            })
+            .collect::<Vec<_>>();
+        let selected = options[0]["dish"]["@id"].clone();
+        json!({
+            "@id": id,
+            "mealType": { "name": id },
+            "dishSize": { "dish": { "@id": selected } },
+            "options": options,
+        })
+    }
+
+    /// Day of `meals`, every option with the same known ingredients
+    fn day(meals: Vec<serde_json::Value>) -> CalendarDayItems {
+        let mut day: CalendarDayItems =
+            serde_json::from_value(json!({ "dietElements": { "hydra:member": meals } })).unwrap();
+        for option in day.options_mut() {
+            option.ingredients = Some(DishSizeIngredients {
+                dish_size_id: option.dish_size_id,
+                ingredients: vec!["ryż".to_string()],
+                fetched_at: None,
+                extra: Default::default(),
+            });
+        }
+        day
+    }
+
+    /// AI answer picking (dish item id, dish id)
+    fn picks(picks: &[(&str, &str)]) -> AiResponse {
+        let selections = picks
+            .iter()
+            .map(|(dish_item, dish_id)| {
+                let pick = json!({ "dish_id": dish_id, "reason": "", "analysis": {} });
+                (dish_item.to_string(), pick)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        serde_json::from_value(json!({ "reasoning": [], "selections": selections })).unwrap()
+    }
+
+    #[test]
+    fn meal_with_only_banned_options_is_kept_and_others_change() {
+        let day = day(vec![
+            meal(
+                "/diet-elements/1",
+                &[
+                    ("/dishes/1", "Krewetki z ryżem", true),
+                    ("/dishes/2", "Pad thai z krewetkami", true),
+                ],
+            ),
+            meal(
+                "/diet-elements/2",
+                &[
+                    ("/dishes/3", "Owsianka", true),
+                    ("/dishes/4", "Jajecznica", true),
+                ],
+            ),
+        ]);
+        let ai = picks(&[
+            ("/diet-elements/1", "/dishes/2"),
+            ("/diet-elements/2", "/dishes/4"),
+        ]);
+        let banned = ["krewetk".to_string()];
+        let members = &day.diet_elements.members;
+
+        assert_eq!(ai_pick_position(&members[0], &ai, &banned), None);
+        assert_eq!(ai_pick_position(&members[1], &ai, &banned), Some(1));
+        let changes = ai_menu_changes_with_banned(&day, &ai, &banned);
+        assert_eq!(
+            changes.items,
+            vec![ChangeMenuItem {
+                dish: "/dishes/4".to_string(),
+                dish_item: "/diet-elements/2".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn disabled_or_unknown_ai_pick_changes_nothing() {
+        let day = day(vec![
+            meal(
+                "/diet-elements/1",
+                &[
+                    ("/dishes/1", "Owsianka", true),
+                    ("/dishes/2", "Pancakes", false),
+                    ("/dishes/3", "Jajecznica", true),
+                ],
+            ),
+            meal(
+                "/diet-elements/2",
+                &[
+                    ("/dishes/4", "Kurczak curry", true),
+                    ("/dishes/5", "Łosoś", true),
+                ],
+            ),
+        ]);
+        let ai = picks(&[
+            ("/diet-elements/1", "/dishes/2"),
+            ("/diet-elements/2", "/dishes/9"),
+        ]);
+        let members = &day.diet_elements.members;
+
+        assert_eq!(ai_pick_position(&members[0], &ai, &[]), None);
+        assert_eq!(ai_pick_position(&members[1], &ai, &[]), None);
+        assert!(ai_menu_changes_with_banned(&day, &ai, &[]).items.is_empty());
+    }
+}
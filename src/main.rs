@@ -1,41 +1,43 @@
-pub mod ai;
-mod api;
-mod preferences;
-pub mod serde;
-
-use crate::api::*;
-use crate::serde::*;
-use ai::{AiResponse, UserAdjustment};
-use chrono::{DateTime, Days, Local, NaiveDate, TimeZone};
-use dialoguer::{theme::ColorfulTheme, Input, Select};
+mod cli;
+mod error_report;
+
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, TimeZone};
+use clap::Parser;
+use cli::{CacheCommand, Cli, Command, PrefsCommand};
+use dialoguer::{theme::ColorfulTheme, Editor, FuzzySelect, Input, Select};
 use eyre::{Context, ContextCompat, OptionExt};
 use indexmap::IndexMap;
-use preferences::Preferences;
+use powermeal_ai_choice::{
+    adjustments_from_selections,
+    ai::{self, AiResponse, UserAdjustment},
+    ai_menu_changes, ai_pick_position,
+    api::*,
+    authenticate,
+    cache::Cache,
+    config::{self, Config, ConfirmationMode, Objective},
+    days_available_to_select, days_available_to_select_from,
+    decisions::{self, Decision},
+    diet_for_date,
+    export::{self, PlannedMeal},
+    fetch_day_menu, fetch_historical_orders, get_diet_with_ingredients, history, history_label,
+    hours_until_cutoff, insert_history_day,
+    notify::{self, RunSummary},
+    preferences::{self, Preferences},
+    recording, runs, save_menu_changes, select_with_ai,
+    serde::*,
+    status::{self, clear_status, notice, status},
+    tags, translate, unusual_option_counts, warm_ingredients_cache, DayMenu,
+};
 use std::{
     collections::HashMap,
-    io::{self, Write},
-    sync::{LazyLock, Mutex},
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
     time::Duration,
 };
-use tokio::time::sleep;
+use tokio::{task::JoinHandle, time::sleep};
 use tracing_subscriber::{layer::SubscriberExt, prelude::*, util::SubscriberInitExt};
 
-static INGREDIENTS_CACHE: LazyLock<Mutex<HashMap<i64, DishSizeIngredients>>> =
-    LazyLock::new(|| Mutex::new(HashMap::new()));
-
-const FETCH_HISTORY_DAYS: i64 = 14;
-
-fn status(txt: &str) {
-    clear_status();
-    print!("{}\r", txt);
-    io::stdout().flush().unwrap();
-}
-
-fn clear_status() {
-    print!("\r\x1b[2K");
-    io::stdout().flush().unwrap();
-}
-
 async fn print_with_delay(message: &str, delay_ms: u64) {
     for c in message.chars() {
         print!("{}", c);
@@ -45,257 +47,1201 @@ async fn print_with_delay(message: &str, delay_ms: u64) {
     println!();
 }
 
+/// Exit status when no day was available, so automation can tell it apart from a run that did something
+const EXIT_NOTHING_TO_DO: u8 = 3;
+
+enum Outcome {
+    Done,
+    NothingToDo,
+}
+
 #[tokio::main]
-async fn main() -> eyre::Result<()> {
+async fn main() -> eyre::Result<ExitCode> {
+    let cli = Cli::parse();
     init_tracing();
-    // dish_stats().await?;
-
-    if Preferences::token().is_none() {
-        print!("Session refresh token is not set.");
-        update_token().await?;
+    status::set_terminal(true);
+    status::set_status_line(!cli.no_status && io::stdout().is_terminal());
+    status::set_verbose(cli.verbose);
+    if let Some(dir) = &cli.record {
+        recording::record_to(dir)?;
+    }
+    if let Some(dir) = &cli.replay {
+        recording::replay_from(dir)?;
     }
 
-    status("Authenticating...");
-    let token =
-        match refresh_token(&Preferences::token().ok_or_eyre("refresh token missing")?).await {
-            Ok(token) => token.token,
-            Err(e) => {
-                clear_status();
-                eprintln!("Error: {}", e);
-                update_token().await?.token
-            }
-        };
+    let result = run_command(&cli).await;
+    report_ai_usage();
+    if let Err(e) = &result {
+        clear_status();
+        error_report::save(e);
+    }
+    Ok(match result? {
+        Outcome::Done => ExitCode::SUCCESS,
+        Outcome::NothingToDo => ExitCode::from(EXIT_NOTHING_TO_DO),
+    })
+}
 
-    let diets = fetch_diets(&token).await.wrap_err("fetch diets")?;
-    let days = days_available_to_select(&token, &diets).await?;
+/// Prints tokens used by the AI in this run and their estimated cost, also logged
+fn report_ai_usage() {
+    let usage = ai::usage();
+    if usage.requests == 0 {
+        return;
+    }
+    clear_status();
+    let summary = format!(
+        "Used {} prompt + {} completion tokens in {} AI requests, ~${:.2}",
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        usage.requests,
+        usage.cost()
+    );
+    tracing::info!("{summary}");
+    println!("{summary}");
+}
 
-    if days.is_empty() {
-        clear_status();
-        println!("No days available to select menu");
-        return Ok(());
+async fn run_command(cli: &Cli) -> eyre::Result<Outcome> {
+    let first_run =
+        cli.command.is_none() && !config::config_path().exists() && io::stdin().is_terminal();
+    if first_run || matches!(cli.command, Some(Command::Setup)) {
+        setup().await?;
     }
 
-    for next_day in days {
-        select_dishes_for_day(&token, next_day, &diets).await?;
+    let mut config = Config::load()?;
+    cli.apply_overrides(&mut config);
+    config::init(config);
+    if cli.refresh_ingredients {
+        Cache::Ingredients.clear()?;
     }
 
-    Ok(())
+    let Some(command) = &cli.command else {
+        return run(cli).await;
+    };
+    match command {
+        Command::Stats { since } => dish_stats(*since).await,
+        Command::Decisions { since } => show_decisions(*since),
+        Command::Setup => Ok(()),
+        Command::DiffRuns => diff_runs(),
+        Command::WarmCache => warm_cache().await,
+        Command::Learn { since } => learn_preferences(*since).await,
+        Command::Compare { date, since } => compare_with_usual(*date, *since).await,
+        Command::Nutrition { date } => review_nutrition(*date).await,
+        Command::SkipDate { date } => {
+            Preferences::exclude_date(*date);
+            println!("{date} will not be offered for selection");
+            Ok(())
+        }
+        Command::Raw { date } => dump_raw_menu(*date).await,
+        Command::Cache { command } => match command {
+            CacheCommand::Info => cache_info(),
+            CacheCommand::Clear {
+                ingredients,
+                translations,
+                all,
+            } => clear_caches(*ingredients, *translations, *all),
+        },
+        Command::Prefs { command } => match command {
+            PrefsCommand::List => list_preferences(),
+            PrefsCommand::Remove { numbers } => remove_preferences(numbers),
+            PrefsCommand::Undo => undo_preferences(),
+            PrefsCommand::Validate => validate_preferences(),
+            PrefsCommand::Test { from, to, reason } => {
+                test_preference(from, to, reason.as_deref()).await
+            }
+        },
+        Command::Replay {
+            menu,
+            history,
+            ingredients,
+            date,
+            stub_ai,
+        } => {
+            replay(
+                menu,
+                history,
+                ingredients.as_deref(),
+                date.unwrap_or_else(|| Local::now().date_naive()),
+                *stub_ai,
+            )
+            .await
+        }
+    }
+    .map(|()| Outcome::Done)
 }
 
-async fn diet_for_date<'a>(
-    token: &str,
-    diet_list: &'a DietsList,
-    date: &DateTime<Local>,
-) -> eyre::Result<Option<&'a Diet>> {
-    if let Some(diet) = diet_list.diet_for_date(date) {
-        return Ok(Some(diet));
+async fn run(cli: &Cli) -> eyre::Result<Outcome> {
+    let mut summary = RunSummary::default();
+    let result = select_available_days(cli, &mut summary).await;
+    if let Err(e) = &result {
+        summary.errors.push(format!("{e:#}"));
     }
 
-    // In case we reschedule some days in the diet, we might get some days that are outside set delivery dates
-    for diet in diet_list.members.iter() {
-        status(&format!(
-            "Searching for diet: Fetching calendar for diet #{}",
-            diet.id
-        ));
-        let calendar = fetch_calendar(
-            token,
-            diet.id,
-            // Extend the range to fetch the calendar
-            if date < &diet.first_delivery_date {
-                date.date_naive()
-            } else {
-                diet.first_delivery_date.date_naive()
-            },
-            if date > &diet.last_delivery_date {
-                date.date_naive()
-            } else {
-                diet.last_delivery_date.date_naive()
-            },
-        )
-        .await
-        .wrap_err("fetch calendar")?;
-        if let Some(diet_day) = calendar.days.get(&date.date_naive()) {
-            if diet_day.state == DietDayState::AvailableToSelect {
-                return Ok(Some(diet));
-            }
+    if result.is_ok() {
+        warn_if_ai_keeps_defaults(&summary);
+    }
+
+    // a dry run leaves no trace, `diff-runs` compares the runs that counted
+    if !cli.dry_run {
+        if let Err(e) = runs::save(&summary.ai_plan) {
+            tracing::warn!("Failed to save the AI's plan: {e:#}");
+        }
+    }
+
+    if let Some(url) = &config::get().notify_webhook {
+        if let Err(e) = notify::send_webhook(url, &summary).await {
+            tracing::warn!("Failed to send run notification: {e:#}");
         }
     }
+    result
+}
 
-    Ok(None)
+fn warn_if_cutoff_near(date: &DateTime<Local>) {
+    match hours_until_cutoff(date) {
+        Some(hours) if hours < config::get().cutoff_warning_hours => {
+            println!(
+                "\x1b[33mThis day's cutoff is in {} hours\x1b[0m",
+                hours.max(0)
+            );
+        }
+        _ => {}
+    }
 }
 
-async fn update_token() -> eyre::Result<RefreshTokenResponse> {
+fn warn_unusual_menu(calendar_day_items: &CalendarDayItems, missing_meals: &[String]) {
+    if !missing_meals.is_empty() {
+        println!(
+            "\x1b[33m{} missing from the menu, it may be incomplete\x1b[0m",
+            missing_meals.join(", ")
+        );
+    }
+    for warning in unusual_option_counts(calendar_day_items) {
+        println!("\x1b[33m{warning}, check the menu\x1b[0m");
+    }
+}
+
+/// Attempts to list diets when PowerMeal can't be reached, the run can't start without them
+const FETCH_DIETS_ATTEMPTS: u32 = 3;
+const FETCH_DIETS_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Diets of the account, retried on connection problems. A rejected session
+/// asks for a new refresh token once and replaces `token`.
+async fn fetch_diets_on_startup(token: &mut String) -> eyre::Result<DietsList> {
+    let mut attempt = 1;
+    let mut reauthenticated = false;
     loop {
-        let token = dialoguer::Input::<String>::new()
-            .with_prompt("Enter your refresh token")
-            .interact()?;
-        match refresh_token(&token).await {
-            Ok(resp) => {
-                Preferences::save_token(&token);
-                return Ok(resp);
-            }
-            Err(e) => {
+        match fetch_diets(token).await {
+            Ok(diets) => return Ok(diets),
+            Err(e @ ApiError::Auth { .. }) => {
+                if reauthenticated {
+                    return Err(eyre::Report::new(e)
+                        .wrap_err("PowerMeal rejected the session while listing diets"));
+                }
                 clear_status();
-                eprintln!("Error: {}", e);
+                eprintln!("PowerMeal rejected the session ({e}), the refresh token may be revoked");
+                *token = update_token().await?.token;
+                reauthenticated = true;
+            }
+            Err(e @ (ApiError::Http(_) | ApiError::RateLimited(_))) => {
+                if attempt >= FETCH_DIETS_ATTEMPTS {
+                    return Err(eyre::Report::new(e).wrap_err(
+                        "Could not reach PowerMeal to list diets, check the connection",
+                    ));
+                }
+                let delay = FETCH_DIETS_RETRY_DELAY * 2u32.pow(attempt - 1);
+                tracing::warn!(
+                    "Listing diets failed ({e}), retrying in {}s",
+                    delay.as_secs()
+                );
+                sleep(delay).await;
+                attempt += 1;
             }
+            Err(e) => return Err(eyre::Report::new(e).wrap_err("fetch diets")),
+        }
+    }
+}
+
+/// Diets of the account, or just `diet_id` when given
+async fn fetch_selected_diets(token: &mut String, diet_id: Option<i64>) -> eyre::Result<DietsList> {
+    let mut diets = fetch_diets_on_startup(token).await?;
+    if let Some(diet_id) = diet_id {
+        if !diets.members.iter().any(|diet| diet.id == diet_id) {
+            let ids = diets
+                .members
+                .iter()
+                .map(|diet| diet.id.to_string())
+                .collect::<Vec<_>>();
+            eyre::bail!(
+                "Diet #{diet_id} is not one of your diets ({})",
+                ids.join(", ")
+            );
         }
+        diets.members.retain(|diet| diet.id == diet_id);
+    }
+    Ok(diets)
+}
+
+/// Below this many picks in a run, keeping every default can be a coincidence
+const MIN_PICKS_FOR_DEFAULTS_WARNING: usize = 6;
+
+/// An AI that never changes anything usually means preferences or config are not applied
+fn warn_if_ai_keeps_defaults(summary: &RunSummary) {
+    if summary.ai_picks >= MIN_PICKS_FOR_DEFAULTS_WARNING
+        && summary.ai_kept_default == summary.ai_picks
+    {
+        println!(
+            "\x1b[33mAI kept the kitchen's default for all {} meals in this run. \
+             If that's unexpected, check that your preferences were saved and the config is right.\x1b[0m",
+            summary.ai_picks
+        );
     }
 }
 
-async fn days_available_to_select(
+/// Days to select in this run, from today with `--reoptimize`
+async fn selectable_days(
+    cli: &Cli,
     token: &str,
     diets: &DietsList,
 ) -> eyre::Result<Vec<DateTime<Local>>> {
-    let mut days = Vec::new();
-    let next_day = preferences::Preferences::next_day_to_check().unwrap_or_else(chrono::Local::now);
-    let end_day = next_day + chrono::Duration::days(14);
+    let selectable = if cli.reoptimize {
+        days_available_to_select_from(token, diets, Local::now()).await?
+    } else {
+        days_available_to_select(token, diets).await?
+    };
+    selectable.remember_pending();
+    Ok(selectable.days)
+}
 
-    #[derive(Debug, PartialEq)]
-    enum DietDayStatus {
-        AvailableToSelect,
-        NotBoughtDiet,
-        Other,
+async fn select_available_days(cli: &Cli, summary: &mut RunSummary) -> eyre::Result<Outcome> {
+    // fail before authenticating and fetching menus, the AI is needed for every day
+    if !config::get().no_ai {
+        ai::api_key()?;
     }
-    let mut diet_day_status: HashMap<NaiveDate, DietDayStatus> = HashMap::new();
 
-    for diet in diets.diets_in_time_range(&next_day, &end_day) {
-        status(&format!("Fetching calendar for diet #{}", diet.id));
-        let calendar = fetch_calendar(token, diet.id, next_day.date_naive(), end_day.date_naive())
-            .await
-            .wrap_err("fetching calendar")?;
-        for (date, status) in calendar.days {
-            if status.state == DietDayState::AvailableToSelect {
-                diet_day_status.insert(date, DietDayStatus::AvailableToSelect);
-                days.push(Local.from_local_datetime(&date.into()).unwrap());
-            } else if status.state == DietDayState::NotBoughtDiet {
-                diet_day_status
-                    .entry(date)
-                    .or_insert(DietDayStatus::NotBoughtDiet);
-            } else if matches!(
-                diet_day_status.get(&date),
-                None | Some(DietDayStatus::NotBoughtDiet)
-            ) {
-                diet_day_status.insert(date, DietDayStatus::Other);
+    let mut token = login().await?;
+    let mut diets = fetch_selected_diets(&mut token, cli.diet_id).await?;
+    let mut days = selectable_days(cli, &token, &diets).await?;
+
+    while days.is_empty() {
+        let Some(minutes) = cli.wait else {
+            clear_status();
+            println!("No days available to select menu");
+            return Ok(Outcome::NothingToDo);
+        };
+        status(&format!(
+            "No days available to select menu, checking again in {minutes} minutes..."
+        ));
+        sleep(Duration::from_secs(minutes * 60)).await;
+        // the token may have expired while waiting
+        token = login().await?;
+        diets = fetch_selected_diets(&mut token, cli.diet_id).await?;
+        days = selectable_days(cli, &token, &diets).await?;
+    }
+
+    // remaining days are picked up by the next run, as the next day to check advances per day
+    let days = days
+        .into_iter()
+        .take(cli.max_days.unwrap_or(usize::MAX))
+        .collect::<Vec<_>>();
+    let skips = DaySkips {
+        // a day selected before still needs the meals of this run picked
+        known_menus: if cli.force
+            || cli.propose
+            || cli.dry_run
+            || cli.reoptimize
+            || !cli.meals.is_empty()
+        {
+            IndexMap::new()
+        } else {
+            Preferences::day_fingerprints()
+        },
+        untouched_only: cli.untouched_only,
+        meals: cli.meals.clone(),
+    };
+    let mut planned = Vec::new();
+    let mode = if cli.dry_run {
+        SelectMode::DryRun
+    } else if cli.propose {
+        SelectMode::Propose
+    } else {
+        SelectMode::Change
+    };
+    // a plan saves once accepted, so only runs that change the menu go through it
+    if mode == SelectMode::Change
+        && (cli.plan || config::get().confirmation_mode == ConfirmationMode::PerRun)
+    {
+        plan_days(&token, days, &diets, &skips, summary, &mut planned).await?;
+    } else {
+        select_days(&token, days, &diets, &skips, mode, summary, &mut planned).await?;
+    }
+
+    if let Some(path) = &cli.export_calendar {
+        if planned.is_empty() {
+            println!("No menus to export");
+        } else {
+            export::write_calendar(path, &planned)?;
+            println!("Menu exported to {}", path.display());
+        }
+    }
+    Ok(Outcome::Done)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SelectMode {
+    /// Confirm the picks and save them to the menu
+    Change,
+    /// Print the picks to apply in the app, optionally learning from what was picked instead
+    Propose,
+    /// Print the picks, nothing is asked or saved
+    DryRun,
+}
+
+/// Interactive selection, one day after another
+async fn select_days(
+    token: &str,
+    days: Vec<DateTime<Local>>,
+    diets: &DietsList,
+    skips: &DaySkips,
+    mode: SelectMode,
+    summary: &mut RunSummary,
+    planned: &mut Vec<PlannedMeal>,
+) -> eyre::Result<()> {
+    let mut run_selections = HashMap::new();
+    let mut days = days.into_iter().peekable();
+    let mut prefetched: Option<JoinHandle<eyre::Result<PreparedDay>>> = None;
+    while let Some(date) = days.next() {
+        let mut day = match prefetched.take() {
+            Some(handle) => handle.await.wrap_err("prefetching next day")??,
+            None => {
+                prepare_day(
+                    token,
+                    date,
+                    diets,
+                    run_selections.clone(),
+                    Preferences::get_preferences(),
+                    skips,
+                )
+                .await?
+            }
+        };
+        // the prefetch assumed the AI's picks were taken for the previous day
+        if day
+            .ai
+            .as_ref()
+            .is_some_and(|ai| ai.assumed_selections != run_selections)
+        {
+            day.ai = Some(
+                prepare_ai(
+                    token,
+                    &date,
+                    diets,
+                    &day.calendar_day_items,
+                    run_selections.clone(),
+                    Preferences::get_preferences(),
+                )
+                .await?,
+            );
+        }
+
+        if let Some(next_day) = days.peek() {
+            let mut assumed_selections = run_selections.clone();
+            if let Some(ai) = &day.ai {
+                assumed_selections.insert(
+                    date.date_naive(),
+                    ai_menu_changes(&day.calendar_day_items, &ai.result),
+                );
             }
+            prefetched = Some(spawn_prepare_day(
+                token,
+                *next_day,
+                diets,
+                assumed_selections,
+                skips,
+            ));
+        }
+
+        if mode != SelectMode::Change {
+            propose_dishes_for_day(day, &mut run_selections, mode, summary).await?;
+            summary.days_processed.push(date.date_naive());
+            continue;
         }
+        let changes_made =
+            select_dishes_for_day(token, day, &mut run_selections, summary, planned).await?;
+        summary.changes_made += changes_made;
+        summary.days_processed.push(date.date_naive());
     }
 
-    for (date, status) in diet_day_status {
-        if let DietDayStatus::NotBoughtDiet = status {
-            clear_status();
-            println!("{}: No diet bought", date);
+    Ok(())
+}
+
+/// Days that are left alone in this run
+#[derive(Clone)]
+struct DaySkips {
+    /// Menu fingerprints of days selected before, by date
+    known_menus: IndexMap<NaiveDate, String>,
+    /// Skip days where a dish was already picked by hand
+    untouched_only: bool,
+    /// Meal types picked in this run, empty for all. Other meals are left alone
+    /// and the days are not marked as selected, so a full run still visits them.
+    meals: Vec<String>,
+}
+
+impl DaySkips {
+    /// Whether only some meals are picked in this run
+    fn partial(&self) -> bool {
+        !self.meals.is_empty()
+    }
+
+    /// Locks the meals left alone in this run
+    fn restrict_meals(&self, date: NaiveDate, calendar_day_items: &mut CalendarDayItems) {
+        if !self.partial() {
+            return;
+        }
+        for name in calendar_day_items.lock_meals_except(&self.meals) {
+            notice(&format!(
+                "{date}: No {name} meal on the menu, check the --meals names"
+            ));
         }
     }
 
-    days.sort_unstable();
-    Ok(days)
+    fn reason(&self, date: NaiveDate, calendar_day_items: &CalendarDayItems) -> Option<SkipReason> {
+        if self.known_menus.get(&date) == Some(&calendar_day_items.fingerprint()) {
+            Some(SkipReason::Unchanged)
+        } else if self.untouched_only && calendar_day_items.has_manual_selection() {
+            Some(SkipReason::ManuallySelected)
+        } else {
+            None
+        }
+    }
 }
 
-async fn get_diet_with_ingredients(
-    date: &DateTime<Local>,
-    diet_id: i64,
-    token: &str,
-) -> eyre::Result<CalendarDayItems> {
-    let mut calendar_day_items = get_diet(date, diet_id, token).await?;
-    for dish_item in &mut calendar_day_items.diet_elements.members {
-        for option in &mut dish_item.options {
-            if option.ingredients.is_none() {
-                // Try to get ingredients from cache first
-                {
-                    let ingredients_cache = INGREDIENTS_CACHE.lock().unwrap();
-
-                    option.ingredients = ingredients_cache.get(&option.dish_size_id).cloned();
-                }
+#[derive(Clone, Copy)]
+enum SkipReason {
+    /// Menu is the same as when the day was selected before
+    Unchanged,
+    /// A dish other than the default is selected and only untouched days are wanted
+    ManuallySelected,
+}
 
-                // still nothing, fetch from api
-                if option.ingredients.is_none() {
-                    status(&format!(
-                        "Fetching ingredients for {}",
-                        option.name.as_str()
-                    ));
-                    let ingredients = fetch_ingredients(token, option.dish_size_id)
-                        .await
-                        .wrap_err("fetching ingredients")?;
-                    // cache ingredients
-                    {
-                        let mut ingredients_cache = INGREDIENTS_CACHE.lock().unwrap();
-                        ingredients_cache.insert(option.dish_size_id, ingredients.clone());
-                    }
-                    option.ingredients = Some(ingredients);
-                }
+impl SkipReason {
+    fn message(self) -> &'static str {
+        match self {
+            SkipReason::Unchanged => {
+                "menu didn't change since it was selected, skipping (--force selects it again)"
             }
+            SkipReason::ManuallySelected => "dishes already picked by hand, skipping",
         }
     }
-    Ok(calendar_day_items)
 }
 
-async fn select_dishes_for_day(
+/// Menu and AI picks of a day, gathered before asking the user anything
+struct PreparedDay {
+    date: DateTime<Local>,
+    diet_id: i64,
+    calendar_day_items: CalendarDayItems,
+    /// Meals of the diet absent from the menu
+    missing_meals: Vec<String>,
+    skipped: Option<SkipReason>,
+    /// None when all meals are locked or the day is skipped
+    ai: Option<PreparedAi>,
+    /// Only some meals are picked, see [`DaySkips::meals`]
+    partial: bool,
+}
+
+struct PreparedAi {
+    result: AiResponse,
+    /// Selections of earlier days in this run the AI saw in history
+    assumed_selections: HashMap<NaiveDate, ChangeMenuRequest>,
+}
+
+async fn prepare_day(
     token: &str,
     date: DateTime<Local>,
     diets: &DietsList,
-) -> eyre::Result<()> {
+    run_selections: HashMap<NaiveDate, ChangeMenuRequest>,
+    user_changes: Vec<UserAdjustment>,
+    skips: &DaySkips,
+) -> eyre::Result<PreparedDay> {
     status("Fetching menu...");
-    let diet_id = diet_for_date(token, diets, &date)
-        .await
-        .wrap_err_with(|| format!("find diet day for {date}"))?
-        .ok_or_else(|| eyre::eyre!("no diet for date {date}"))?
-        .id;
-    let calendar_day_items = get_diet_with_ingredients(&date, diet_id, token)
+    let DayMenu {
+        diet_id,
+        mut calendar_day_items,
+        missing_meals,
+        ..
+    } = fetch_day_menu(token, &date, diets).await?;
+    skips.restrict_meals(date.date_naive(), &mut calendar_day_items);
+    let skipped = skips.reason(date.date_naive(), &calendar_day_items);
+    let ai = if skipped.is_some() || calendar_day_items.all_locked() {
+        None
+    } else {
+        Some(
+            prepare_ai(
+                token,
+                &date,
+                diets,
+                &calendar_day_items,
+                run_selections,
+                user_changes,
+            )
+            .await?,
+        )
+    };
+    Ok(PreparedDay {
+        date,
+        diet_id,
+        calendar_day_items,
+        missing_meals,
+        skipped,
+        ai,
+        partial: skips.partial(),
+    })
+}
+
+async fn prepare_ai(
+    token: &str,
+    date: &DateTime<Local>,
+    diets: &DietsList,
+    calendar_day_items: &CalendarDayItems,
+    run_selections: HashMap<NaiveDate, ChangeMenuRequest>,
+    user_changes: Vec<UserAdjustment>,
+) -> eyre::Result<PreparedAi> {
+    let result = select_with_ai(
+        token,
+        date,
+        diets,
+        calendar_day_items,
+        &run_selections,
+        user_changes,
+    )
+    .await?;
+    clear_status();
+    Ok(PreparedAi {
+        result,
+        assumed_selections: run_selections,
+    })
+}
+
+/// Prepares the next day in the background while the user works on the current one
+fn spawn_prepare_day(
+    token: &str,
+    date: DateTime<Local>,
+    diets: &DietsList,
+    assumed_selections: HashMap<NaiveDate, ChangeMenuRequest>,
+    skips: &DaySkips,
+) -> JoinHandle<eyre::Result<PreparedDay>> {
+    let token = token.to_string();
+    let diets = diets.clone();
+    let skips = skips.clone();
+    // read now, the prefetch is redone anyway when the user overrides the AI
+    let user_changes = Preferences::get_preferences();
+    tokio::spawn(status::quietly(async move {
+        prepare_day(
+            &token,
+            date,
+            &diets,
+            assumed_selections,
+            user_changes,
+            &skips,
+        )
         .await
-        .wrap_err("getting diet with ingredients")?;
+    }))
+}
+
+async fn login() -> eyre::Result<String> {
+    // the recorded token response is used whatever the token
+    if recording::replaying() {
+        return authenticate(&Preferences::token().unwrap_or_default()).await;
+    }
+    if Preferences::token().is_none() {
+        print!("Session refresh token is not set.");
+        update_token().await?;
+    }
+
+    status("Authenticating...");
+    let token = match authenticate(&Preferences::token().ok_or_eyre("refresh token missing")?).await
+    {
+        Ok(token) => token,
+        Err(e) => {
+            clear_status();
+            eprintln!("Error: {}", e);
+            update_token().await?.token
+        }
+    };
+    Ok(token)
+}
+
+async fn update_token() -> eyre::Result<RefreshTokenResponse> {
+    if !io::stdin().is_terminal() {
+        eyre::bail!("A new refresh token is needed, run once interactively to enter it");
+    }
+    loop {
+        let token = dialoguer::Input::<String>::new()
+            .with_prompt("Enter your refresh token")
+            .interact()?;
+        match refresh_token(&token).await {
+            Ok(resp) => {
+                Preferences::save_token(&token);
+                return Ok(resp);
+            }
+            Err(e) => {
+                clear_status();
+                eprintln!("Error: {}", e);
+            }
+        }
+    }
+}
+
+/// Prints the AI's picks to apply by hand, without changing the menu. The user
+/// may tell what they picked instead, to learn preferences from it.
+async fn propose_dishes_for_day(
+    day: PreparedDay,
+    run_selections: &mut HashMap<NaiveDate, ChangeMenuRequest>,
+    mode: SelectMode,
+    summary: &mut RunSummary,
+) -> eyre::Result<()> {
+    let PreparedDay {
+        date,
+        mut calendar_day_items,
+        missing_meals,
+        skipped,
+        ai,
+        ..
+    } = day;
     clear_status();
+    if let Some(reason) = skipped {
+        println!("{}: {}", date.format("%Y-%m-%d"), reason.message());
+        return Ok(());
+    }
     println!("{}, {}", date.format("%Y-%m-%d"), date.format("%A"));
+    warn_if_cutoff_near(&date);
+    warn_unusual_menu(&calendar_day_items, &missing_meals);
+    translate_menu(&mut calendar_day_items).await;
     println!("{}", calendar_day_items.debug_options());
-    let last_days_choices = fetch_historical_orders(token, diets, &date, FETCH_HISTORY_DAYS)
-        .await
-        .wrap_err("fetching historical orders")?;
-    status("Ai is thinking...");
-    let result = ai::select_dish(
-        date.date_naive(),
-        &calendar_day_items.diet_elements.members,
-        &last_days_choices,
-    )
-    .await
-    .wrap_err("selecting dish with ai")?;
-    clear_status();
-    println!();
+    let Some(PreparedAi { result, .. }) = ai else {
+        println!("All meals are locked, nothing to select");
+        println!();
+        return Ok(());
+    };
 
     for reason in &result.reasoning {
         print_with_delay(&format!(" 𝔞𝔦 {}", reason), 1).await;
     }
-
-    let mut menu_changes = ChangeMenuRequest::default();
-    let new_preferences = select_dishes(
-        &calendar_day_items,
-        &date.date_naive(),
-        result,
-        &mut menu_changes,
-    )
-    .await
-    .wrap_err("while asking user")?;
-
-    if !new_preferences.is_empty() {
-        confirm_preferences_save(new_preferences).await?;
+    println!();
+    println!("Recommendations:");
+    for dish_item in &calendar_day_items.diet_elements.members {
+        let Some(pick) = result.selections.get(&dish_item.id) else {
+            continue;
+        };
+        let Some(dish) = dish_item.get_dish(&pick.dish_id) else {
+            continue;
+        };
+        let current = dish_item.get_selected_option().map(|dish| &dish.dish.id);
+        println!(
+            "  \x1b[1m{}\x1b[0m pick {}{}",
+            dish_item.display_name(),
+            dish.name,
+            if current == Some(&dish.dish.id) {
+                " (already selected)"
+            } else {
+                ""
+            }
+        );
     }
+    println!();
 
-    if !menu_changes.items.is_empty() {
-        confirm_menu_change(
-            token,
-            &date.date_naive(),
-            diet_id,
-            &menu_changes,
+    summary
+        .ai_plan
+        .extend(runs::picks(date.date_naive(), &calendar_day_items, &result));
+
+    // later days are picked assuming the recommendations are applied
+    let mut menu_changes = ai_menu_changes(&calendar_day_items, &result);
+    if mode == SelectMode::Propose
+        && config::get().confirmation_mode != ConfirmationMode::None
+        && dialoguer::Confirm::new()
+            .with_prompt("Tell what you picked in the app, to learn from it?")
+            .default(false)
+            .interact()?
+    {
+        menu_changes = ChangeMenuRequest::default();
+        let mut day_decisions = Vec::new();
+        let new_preferences = select_dishes(
             &calendar_day_items,
+            &date.date_naive(),
+            result,
+            &mut menu_changes,
+            &mut day_decisions,
+            false,
         )
         .await
-        .wrap_err("confirm menu change")?;
+        .wrap_err("while asking user")?;
+        if !new_preferences.is_empty() {
+            confirm_preferences_save(new_preferences).await?;
+        }
+        // picked by hand in the app, so what was told is what the menu has
+        record_decisions(&mut day_decisions, &calendar_day_items, Some(&menu_changes));
     }
-    Preferences::set_next_day_to_check(date.date_naive().checked_add_days(Days::new(1)).unwrap());
+    run_selections.insert(date.date_naive(), menu_changes);
     Ok(())
 }
 
-async fn confirm_preferences_save(new_preferences: Vec<UserAdjustment>) -> eyre::Result<()> {
+/// Returns number of menu changes saved for the day
+/// `run_selections` collects menu changes of days already processed in this run,
+/// so later days see them in history even if they were not saved.
+async fn select_dishes_for_day(
+    token: &str,
+    day: PreparedDay,
+    run_selections: &mut HashMap<NaiveDate, ChangeMenuRequest>,
+    summary: &mut RunSummary,
+    planned: &mut Vec<PlannedMeal>,
+) -> eyre::Result<usize> {
+    let PreparedDay {
+        date,
+        diet_id,
+        mut calendar_day_items,
+        missing_meals,
+        skipped,
+        ai,
+        partial,
+    } = day;
+    clear_status();
+    if let Some(reason) = skipped {
+        println!("{}: {}", date.format("%Y-%m-%d"), reason.message());
+        planned.extend(export::planned_meals(
+            date.date_naive(),
+            &calendar_day_items,
+        ));
+        if !partial {
+            Preferences::set_next_day_to_check(
+                date.date_naive().checked_add_days(Days::new(1)).unwrap(),
+            );
+        }
+        return Ok(0);
+    }
+    println!("{}, {}", date.format("%Y-%m-%d"), date.format("%A"));
+    warn_if_cutoff_near(&date);
+    warn_unusual_menu(&calendar_day_items, &missing_meals);
+    translate_menu(&mut calendar_day_items).await;
+    println!("{}", calendar_day_items.debug_options());
+    let extra_fields = calendar_day_items.debug_extra_fields();
+    if !extra_fields.is_empty() {
+        println!("Other items available for this day:\n{}", extra_fields);
+    }
+    let Some(PreparedAi { result, .. }) = ai else {
+        println!("All meals are locked, nothing to select");
+        println!();
+        record_history(date.date_naive(), &calendar_day_items, None);
+        planned.extend(export::planned_meals(
+            date.date_naive(),
+            &calendar_day_items,
+        ));
+        if !partial {
+            Preferences::save_day_fingerprint(date.date_naive(), calendar_day_items.fingerprint());
+            Preferences::set_next_day_to_check(
+                date.date_naive().checked_add_days(Days::new(1)).unwrap(),
+            );
+        }
+        return Ok(0);
+    };
+    println!();
+    summary.record_ai_picks(
+        result.selections.len(),
+        ai_menu_changes(&calendar_day_items, &result).items.len(),
+        result.heuristic,
+    );
+    summary
+        .ai_plan
+        .extend(runs::picks(date.date_naive(), &calendar_day_items, &result));
+
+    for reason in &result.reasoning {
+        print_with_delay(&format!(" 𝔞𝔦 {}", reason), 1).await;
+    }
+
+    let mut menu_changes = ChangeMenuRequest::default();
+    let mut day_decisions = Vec::new();
+    let new_preferences = select_dishes(
+        &calendar_day_items,
+        &date.date_naive(),
+        result,
+        &mut menu_changes,
+        &mut day_decisions,
+        true,
+    )
+    .await
+    .wrap_err("while asking user")?;
+
+    if !new_preferences.is_empty() {
+        confirm_preferences_save(new_preferences).await?;
+    }
+
+    if menu_changes.items.is_empty() {
+        let date_label = date.format("%Y-%m-%d");
+        if day_decisions.iter().all(|decision| decision.accepted) {
+            println!("AI agrees with current menu for {date_label}, nothing to change");
+        } else {
+            println!("Current menu kept for {date_label}, nothing to change");
+        }
+    }
+
+    let mut changes_saved = 0;
+    let mut declined = false;
+    if !menu_changes.items.is_empty() {
+        let answer = confirm_menu_change(
+            token,
+            &date.date_naive(),
+            diet_id,
+            &mut menu_changes,
+            &calendar_day_items,
+        )
+        .await
+        .wrap_err("confirm menu change")?;
+        if answer.saved {
+            changes_saved = menu_changes.items.len();
+        }
+        declined = answer.refused;
+    }
+    record_history(
+        date.date_naive(),
+        &calendar_day_items,
+        (changes_saved > 0).then_some(&menu_changes),
+    );
+    record_decisions(
+        &mut day_decisions,
+        &calendar_day_items,
+        (changes_saved > 0).then_some(&menu_changes),
+    );
+    let mut calendar_day_items = calendar_day_items;
+    if changes_saved > 0 {
+        calendar_day_items.apply_changes(&menu_changes);
+    }
+    print_surcharges(date.date_naive(), &calendar_day_items, summary);
+    planned.extend(export::planned_meals(
+        date.date_naive(),
+        &calendar_day_items,
+    ));
+    // later days must not see refused changes in their history
+    if changes_saved > 0 {
+        run_selections.insert(date.date_naive(), menu_changes);
+    }
+    // a declined day is offered again, even when its menu stays the same
+    if !partial && !declined {
+        Preferences::save_day_fingerprint(date.date_naive(), calendar_day_items.fingerprint());
+        Preferences::set_next_day_to_check(
+            date.date_naive().checked_add_days(Days::new(1)).unwrap(),
+        );
+    }
+    Preferences::clear_day_progress();
+    Ok(changes_saved)
+}
+
+struct DayPlan {
+    date: DateTime<Local>,
+    diet_id: i64,
+    calendar_day_items: CalendarDayItems,
+    missing_meals: Vec<String>,
+    menu_changes: ChangeMenuRequest,
+    decisions: Vec<Decision>,
+}
+
+/// Runs the AI for all `days` without asking, then shows the whole plan for a single confirmation
+async fn plan_days(
+    token: &str,
+    days: Vec<DateTime<Local>>,
+    diets: &DietsList,
+    skips: &DaySkips,
+    summary: &mut RunSummary,
+    planned: &mut Vec<PlannedMeal>,
+) -> eyre::Result<()> {
+    let day_count = days.len();
+    let mut run_selections = HashMap::new();
+    let mut plans = Vec::new();
+    let mut cost_confirmed = false;
+    let unsaved_changes = Preferences::unsaved_changes();
+    for date in days {
+        status(&format!("Fetching menu for {}...", date.format("%Y-%m-%d")));
+        let diet = diet_for_date(token, diets, &date)
+            .await
+            .wrap_err_with(|| format!("find diet day for {date}"))?
+            .ok_or_else(|| eyre::eyre!("no diet for date {date}"))?;
+        let diet_id = diet.id;
+        let mut calendar_day_items = get_diet_with_ingredients(&date, diet_id, token)
+            .await
+            .wrap_err("getting diet with ingredients")?;
+        skips.restrict_meals(date.date_naive(), &mut calendar_day_items);
+        let missing_meals = diet.missing_meal_types(&calendar_day_items);
+        if let Some(reason) = skips.reason(date.date_naive(), &calendar_day_items) {
+            clear_status();
+            println!("{}: {}", date.format("%Y-%m-%d"), reason.message());
+            planned.extend(export::planned_meals(
+                date.date_naive(),
+                &calendar_day_items,
+            ));
+            continue;
+        }
+
+        let unsaved = unsaved_changes
+            .get(&date.date_naive())
+            .filter(|menu_changes| {
+                calendar_day_items
+                    .validate_changes(diet_id, menu_changes)
+                    .is_ok()
+            });
+        let (menu_changes, decisions) = if calendar_day_items.all_locked() {
+            (ChangeMenuRequest::default(), Vec::new())
+        } else if let Some(menu_changes) = unsaved {
+            // planned before but failed to save, no need to ask the AI again
+            notice(&format!(
+                "{}: Using the plan that failed to save last time",
+                date.format("%Y-%m-%d")
+            ));
+            (menu_changes.clone(), Vec::new())
+        } else {
+            let last_days_choices = fetch_historical_orders(
+                token,
+                diets,
+                &date,
+                config::get().history_days,
+                &run_selections,
+            )
+            .await
+            .wrap_err("fetching historical orders")?;
+            if !cost_confirmed
+                && !config::get().no_ai
+                && config::get().confirmation_mode != ConfirmationMode::None
+            {
+                // later days have a similar request, so the first one stands for all of them
+                if !confirm_plan_cost(&date, &calendar_day_items, &last_days_choices, day_count)? {
+                    println!("Nothing planned");
+                    return Ok(());
+                }
+                cost_confirmed = true;
+            }
+            status(&format!("Ai is planning {}...", date.format("%Y-%m-%d")));
+            let result = ai::select_dish(
+                date.date_naive(),
+                &calendar_day_items.diet_elements.members,
+                &last_days_choices,
+            )
+            .await
+            .wrap_err("selecting dish with ai")?;
+            let menu_changes = ai_menu_changes(&calendar_day_items, &result);
+            summary.record_ai_picks(
+                result.selections.len(),
+                menu_changes.items.len(),
+                result.heuristic,
+            );
+            summary
+                .ai_plan
+                .extend(runs::picks(date.date_naive(), &calendar_day_items, &result));
+            let decisions =
+                decisions::accepted_ai_picks(date.date_naive(), &calendar_day_items, &result);
+            (menu_changes, decisions)
+        };
+        run_selections.insert(date.date_naive(), menu_changes.clone());
+        plans.push(DayPlan {
+            date,
+            diet_id,
+            calendar_day_items,
+            missing_meals,
+            menu_changes,
+            decisions,
+        });
+    }
+    clear_status();
+
+    for plan in &plans {
+        println!(
+            "{}, {}",
+            plan.date.format("%Y-%m-%d"),
+            plan.date.format("%A")
+        );
+        warn_if_cutoff_near(&plan.date);
+        warn_unusual_menu(&plan.calendar_day_items, &plan.missing_meals);
+        if plan.menu_changes.items.is_empty() {
+            println!("Keeping the current menu");
+        } else {
+            print_menu_changes(&plan.menu_changes, &plan.calendar_day_items)?;
+        }
+        println!();
+    }
+
+    let changed_days = plans
+        .iter()
+        .filter(|plan| !plan.menu_changes.items.is_empty())
+        .count();
+    let confirmed = changed_days == 0
+        || config::get().confirmation_mode == ConfirmationMode::None
+        || dialoguer::Confirm::new()
+            .with_prompt(format!("Save menu changes for {changed_days} days?"))
+            .interact()?;
+    if !confirmed {
+        println!("Nothing saved, the days will be offered again on the next run");
+        return Ok(());
+    }
+
+    for plan in &plans {
+        plan.calendar_day_items
+            .validate_changes(plan.diet_id, &plan.menu_changes)
+            .wrap_err_with(|| format!("menu changes for {}", plan.date.date_naive()))?;
+    }
+
+    // a failed day doesn't stop the others, it is kept to be submitted again by the next run
+    let mut results = Vec::new();
+    let mut failed = 0;
+    for mut plan in plans {
+        let date = plan.date.date_naive();
+        let saved = !plan.menu_changes.items.is_empty();
+        if saved {
+            status(&format!("Saving menu changes for {date}..."));
+            let result = save_menu_changes(token, date, plan.diet_id, &plan.menu_changes).await;
+            clear_status();
+            if let Err(e) = result {
+                Preferences::save_unsaved_changes(date, &plan.menu_changes);
+                results.push((date, format!("\x1b[31mfailed\x1b[0m: {e:#}")));
+                failed += 1;
+                continue;
+            }
+            Preferences::clear_unsaved_changes(date);
+            summary.changes_made += plan.menu_changes.items.len();
+        }
+        record_history(
+            date,
+            &plan.calendar_day_items,
+            saved.then_some(&plan.menu_changes),
+        );
+        record_decisions(
+            &mut plan.decisions,
+            &plan.calendar_day_items,
+            saved.then_some(&plan.menu_changes),
+        );
+        let mut calendar_day_items = plan.calendar_day_items;
+        calendar_day_items.apply_changes(&plan.menu_changes);
+        print_surcharges(date, &calendar_day_items, summary);
+        planned.extend(export::planned_meals(date, &calendar_day_items));
+        summary.days_processed.push(date);
+        if !skips.partial() {
+            Preferences::save_day_fingerprint(date, calendar_day_items.fingerprint());
+            // the next run has to start at a failed day to submit it again
+            if failed == 0 {
+                Preferences::set_next_day_to_check(date.checked_add_days(Days::new(1)).unwrap());
+            }
+        }
+        results.push((
+            date,
+            if saved {
+                "\x1b[32msaved\x1b[0m".to_string()
+            } else {
+                "unchanged".to_string()
+            },
+        ));
+    }
+
+    println!();
+    for (date, result) in &results {
+        println!("{date}  {result}");
+    }
+    if failed > 0 {
+        eyre::bail!(
+            "Menu changes for {failed} days failed to save, run with --plan again to retry them"
+        );
+    }
+    Ok(())
+}
+
+/// Translates dish names for `translate_to`, the Polish names are still shown when it fails
+async fn translate_menu(calendar_day_items: &mut CalendarDayItems) {
+    if config::get().translate_to.is_none() {
+        return;
+    }
+    status("Translating dish names...");
+    let result = translate::translate_menu(calendar_day_items).await;
+    clear_status();
+    if let Err(e) = result {
+        tracing::warn!("Failed to translate dish names: {e:#}");
+    }
+}
+
+fn print_surcharges(
+    date: NaiveDate,
+    calendar_day_items: &CalendarDayItems,
+    summary: &mut RunSummary,
+) {
+    let Some(total) = calendar_day_items.surcharge_total() else {
+        return;
+    };
+    summary.surcharges += total;
+    println!("Surcharges for {date}: {total:+.2} zł");
+}
+
+fn confirm_plan_cost(
+    date: &DateTime<Local>,
+    calendar_day_items: &CalendarDayItems,
+    last_days_choices: &IndexMap<String, CalendarDayItems>,
+    day_count: usize,
+) -> eyre::Result<bool> {
+    let request = ai::build_request(
+        date.date_naive(),
+        &calendar_day_items.diet_elements.members,
+        last_days_choices,
+        Preferences::get_preferences(),
+    )?;
+    let estimate = ai::estimate_usage(&request)?;
+    clear_status();
+    let confirmed = dialoguer::Confirm::new()
+        .with_prompt(format!(
+            "Planning {} days is estimated at ~{} tokens (up to {} in responses), ~${:.2}. Proceed?",
+            day_count,
+            estimate.input_tokens * day_count,
+            estimate.max_output_tokens * day_count,
+            estimate.cost * day_count as f64
+        ))
+        .default(true)
+        .interact()?;
+    Ok(confirmed)
+}
+
+fn record_history(
+    date: NaiveDate,
+    calendar_day_items: &CalendarDayItems,
+    changes: Option<&ChangeMenuRequest>,
+) {
+    if !config::get().record_history {
+        return;
+    }
+    if let Err(e) = history::record_day(date, calendar_day_items, changes) {
+        tracing::warn!("Failed to record menu history: {e:#}");
+    }
+}
+
+fn record_decisions(
+    decisions: &mut [Decision],
+    calendar_day_items: &CalendarDayItems,
+    saved_changes: Option<&ChangeMenuRequest>,
+) {
+    if !config::get().record_decisions {
+        return;
+    }
+    decisions::mark_saved(decisions, calendar_day_items, saved_changes);
+    if let Err(e) = decisions::record(decisions) {
+        tracing::warn!("Failed to record decisions: {e:#}");
+    }
+}
+
+/// Colors are left out when NO_COLOR is set or the output is not a terminal
+fn colors_enabled() -> bool {
+    std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && io::stdout().is_terminal()
+}
+
+fn print_preferences(new_preferences: &[UserAdjustment]) {
     println!("New preferences:");
-    for pref in &new_preferences {
+    if config::get().plain_preference_diff || !colors_enabled() {
+        for pref in new_preferences {
+            println!("  - {}", pref.from);
+            println!("  + {}", pref.to);
+            if let Some(reason) = &pref.reason {
+                println!("    because: {reason}");
+            }
+        }
+        return;
+    }
+    for pref in new_preferences {
         println!(
             "  \x1b[31m{}\x1b[0m -> \x1b[32m{}\x1b[0m{}",
             pref.from,
@@ -306,10 +1252,30 @@ async fn confirm_preferences_save(new_preferences: Vec<UserAdjustment>) -> eyre:
                 .unwrap_or_default()
         );
     }
-    if dialoguer::Confirm::new()
-        .with_prompt("Add new preferences?")
-        .interact()?
-    {
+}
+
+async fn confirm_preferences_save(mut new_preferences: Vec<UserAdjustment>) -> eyre::Result<()> {
+    print_preferences(&new_preferences);
+    let confirmed = match config::get().confirmation_mode {
+        ConfirmationMode::None => true,
+        ConfirmationMode::PerMeal => {
+            let mut answers = Vec::new();
+            for pref in &new_preferences {
+                answers.push(
+                    dialoguer::Confirm::new()
+                        .with_prompt(format!("Add {} -> {}?", pref.from, pref.to))
+                        .interact()?,
+                );
+            }
+            let mut answers = answers.into_iter();
+            new_preferences.retain(|_| answers.next().unwrap());
+            !new_preferences.is_empty()
+        }
+        ConfirmationMode::PerDay | ConfirmationMode::PerRun => dialoguer::Confirm::new()
+            .with_prompt("Add new preferences?")
+            .interact()?,
+    };
+    if confirmed {
         preferences::Preferences::add_new_preferences(new_preferences);
         println!("Preferences saved");
     }
@@ -317,10 +1283,71 @@ async fn confirm_preferences_save(new_preferences: Vec<UserAdjustment>) -> eyre:
     Ok(())
 }
 
+/// What the user answered to the proposed menu changes
+struct MenuChangeAnswer {
+    /// Whether the changes left in `menu_changes` were saved
+    saved: bool,
+    /// Whether any proposed change was refused
+    refused: bool,
+}
+
+/// Asks to save `menu_changes` and saves them, in `per-meal` mode only the accepted
+/// ones are kept in `menu_changes`
 async fn confirm_menu_change(
     token: &str,
     date: &NaiveDate,
     diet_id: i64,
+    menu_changes: &mut ChangeMenuRequest,
+    calendar_day_items: &CalendarDayItems,
+) -> eyre::Result<MenuChangeAnswer> {
+    calendar_day_items.validate_changes(diet_id, menu_changes)?;
+    print_menu_changes(menu_changes, calendar_day_items)?;
+    let mut refused = false;
+    let confirmed = match config::get().confirmation_mode {
+        ConfirmationMode::None => true,
+        ConfirmationMode::PerMeal => {
+            let mut answers = Vec::new();
+            for item in &menu_changes.items {
+                let meal = calendar_day_items
+                    .get_dish_item(&item.dish_item)
+                    .map(|dish_item| dish_item.display_name())
+                    .unwrap_or_default();
+                answers.push(
+                    dialoguer::Confirm::new()
+                        .with_prompt(format!("Change {meal}?"))
+                        .interact()?,
+                );
+            }
+            // checked before `retain`, refusing every meal leaves nothing to tell from
+            refused = answers.contains(&false);
+            let mut answers = answers.into_iter();
+            menu_changes.items.retain(|_| answers.next().unwrap());
+            !menu_changes.items.is_empty()
+        }
+        ConfirmationMode::PerDay | ConfirmationMode::PerRun => {
+            let confirmed = dialoguer::Confirm::new()
+                .with_prompt("Save menu changes?")
+                .interact()?;
+            refused = !confirmed;
+            confirmed
+        }
+    };
+    if confirmed {
+        status("Saving menu changes...");
+        let result = save_menu_changes(token, *date, diet_id, menu_changes).await;
+        clear_status();
+        // reported once, by the caller
+        result.wrap_err("saving menu changes")?;
+        println!("Menu changes saved");
+    }
+    println!();
+    Ok(MenuChangeAnswer {
+        saved: confirmed,
+        refused,
+    })
+}
+
+fn print_menu_changes(
     menu_changes: &ChangeMenuRequest,
     calendar_day_items: &CalendarDayItems,
 ) -> eyre::Result<()> {
@@ -337,59 +1364,46 @@ async fn confirm_menu_change(
                 tracing::warn!("Dish not found: {}", item.dish);
                 "Unknown dish".to_string()
             });
-        println!("\x1b[1m{}\x1b[0m", dish_item.meal_type.name);
+        println!("\x1b[1m{}\x1b[0m", dish_item.display_name());
         println!(
             "  \x1b[31m{}\x1b[0m -> \x1b[32m{}\x1b[0m",
             current_name, new_name
         );
     }
-    if dialoguer::Confirm::new()
-        .with_prompt("Save menu changes?")
-        .interact()?
-    {
-        status("Saving menu changes...");
-        change_menu(token, date, diet_id, menu_changes).await?;
-        clear_status();
-    }
-    println!();
     Ok(())
 }
 
-async fn fetch_historical_orders(
-    token: &str,
-    diets: &DietsList,
-    date: &DateTime<Local>,
-    days: i64,
-) -> eyre::Result<IndexMap<String, CalendarDayItems>> {
-    let mut last_days_choices = IndexMap::new();
-    for day in (1..=days).rev() {
-        let date = date
-            .checked_sub_signed(chrono::Duration::days(day))
-            .unwrap();
-        status(&format!(
-            "Fetching menu for {} (-{} days)",
-            date.format("%Y-%m-%d"),
-            day
-        ));
-        if let Some(diet) = diet_for_date(token, diets, &date)
-            .await
-            .wrap_err_with(|| format!("find diet day for {date}"))?
-        {
-            let calendar_day_items = get_diet_with_ingredients(&date, diet.id, token).await?;
-            last_days_choices.insert(
-                if day == 1 {
-                    "yesterday".to_string()
-                } else {
-                    format!("{day} days ago")
-                },
-                calendar_day_items,
-            );
-        } else {
-            clear_status();
-            println!("No diet active for {}", date.format("%Y-%m-%d"));
+/// Shows the AI picks for the whole day and asks whether to take them all at once
+fn confirm_ai_plan(
+    calendar_day_items: &CalendarDayItems,
+    ai_result: &AiResponse,
+) -> eyre::Result<bool> {
+    if config::get().confirmation_mode == ConfirmationMode::None {
+        return Ok(true);
+    }
+    println!("AI plan:");
+    for dish_item in &calendar_day_items.diet_elements.members {
+        if dish_item.is_locked() {
+            continue;
         }
+        let Some(ai) = ai_result.selections.get(&dish_item.id) else {
+            continue;
+        };
+        println!(
+            "  \x1b[1m{}\x1b[0m {}",
+            dish_item.meal_type.name,
+            dish_item
+                .get_dish(&ai.dish_id)
+                .map(|d| d.name.as_str())
+                .unwrap_or("unknown")
+        );
     }
-    Ok(last_days_choices)
+    let accepted = dialoguer::Confirm::new()
+        .with_prompt("Accept AI's full plan?")
+        .default(true)
+        .interact()?;
+    println!();
+    Ok(accepted)
 }
 
 async fn select_dishes(
@@ -397,97 +1411,1037 @@ async fn select_dishes(
     date: &NaiveDate,
     ai_result: AiResponse,
     menu_changes: &mut ChangeMenuRequest,
+    decisions: &mut Vec<Decision>,
+    track_progress: bool,
 ) -> eyre::Result<Vec<UserAdjustment>> {
     let mut new_preferences = Vec::new();
+    let resumed = if track_progress {
+        Preferences::day_progress(date)
+    } else {
+        None
+    };
     println!();
+    let accept_all = confirm_ai_plan(calendar_day_items, &ai_result)?;
     for dish_item in &calendar_day_items.diet_elements.members {
-        let ai = ai_result.selections.get(&dish_item.id).unwrap();
-        let ai_selected = dish_item
-            .options()
-            .iter()
-            .position(|x| x.dish.id == ai.dish_id)
-            .unwrap();
+        if dish_item.is_locked() {
+            println!(
+                "\x1b[1m{}\x1b[0m {} (locked)",
+                dish_item.meal_type.name,
+                dish_item
+                    .get_selected_option()
+                    .map(|x| x.name.as_str())
+                    .unwrap_or("unknown")
+            );
+            println!();
+            continue;
+        }
+        let current = dish_item
+            .get_selected_option()
+            .and_then(|x| dish_item.option_position(&x.dish.id));
 
-        for (dish_id, analysis) in ai.analysis.iter() {
-            print_with_delay(
-                &format!(
-                    " 𝔞𝔦 \x1b[1m{}\x1b[0m {}",
-                    dish_item
-                        .get_dish(dish_id)
-                        .map(|d| d.name.as_str())
-                        .unwrap_or("unknown"),
-                    analysis
-                ),
-                1,
-            )
-            .await;
+        let banned = &config::get().banned_ingredients;
+        let ai = ai_result.selections.get(&dish_item.id);
+        let mut user_reason = None;
+        let ai_pick = ai_pick_position(dish_item, &ai_result, banned);
+        // The schema only allows enabled dishes, but don't trust the model blindly
+        let ai_selected = match ai_pick {
+            Some(ai_selected) => ai_selected,
+            // nobody is asked, the meal stays as it is and the other meals are still selected
+            None if config::get().confirmation_mode == ConfirmationMode::None => {
+                println!(
+                    "\x1b[33mAI did not pick an available dish for {} on {date}, keeping the current one\x1b[0m",
+                    dish_item.meal_type.name
+                );
+                println!();
+                continue;
+            }
+            None => {
+                let safe = |i: &usize| dish_item.options()[*i].banned(banned).is_none();
+                match current.filter(safe) {
+                    Some(current) => {
+                        println!(
+                            "\x1b[33mAI did not pick an available dish for {}, defaulting to the current one\x1b[0m",
+                            dish_item.meal_type.name
+                        );
+                        current
+                    }
+                    None => {
+                        println!(
+                            "\x1b[31mAI did not pick an available dish for {} and the current one is not known to be free of banned ingredients\x1b[0m",
+                            dish_item.meal_type.name
+                        );
+                        // nothing safe is left, leave the menu as it is
+                        (0..dish_item.options().len())
+                            .find(safe)
+                            .or(current)
+                            .unwrap_or_default()
+                    }
+                }
+            }
+        };
+
+        // Meals already chosen before the previous run was interrupted
+        let resumed_selection = resumed
+            .as_ref()
+            .and_then(|progress| progress.selections.get(&dish_item.id))
+            .and_then(|dish_id| dish_item.option_position(dish_id));
+
+        let selection = if let Some(selection) = resumed_selection {
+            println!(
+                "\x1b[1m{}\x1b[0m {} (resumed)",
+                dish_item.meal_type.name,
+                dish_item.options()[selection].name
+            );
+            selection
+        } else if accept_all {
+            if config::get().confirmation_mode == ConfirmationMode::None {
+                // the plan was not shown for confirmation, keep it in the output for review
+                println!(
+                    "\x1b[1m{}\x1b[0m {}",
+                    dish_item.meal_type.name,
+                    dish_item.options()[ai_selected].name
+                );
+                if let Some(ai) = ai {
+                    println!(" 𝔞𝔦 {}", ai.reason);
+                }
+            }
+            if track_progress {
+                Preferences::save_day_progress(
+                    *date,
+                    &dish_item.id,
+                    &dish_item.options()[ai_selected].dish.id,
+                );
+            }
+            ai_selected
+        } else {
+            if let Some(ai) = ai {
+                for (dish_id, analysis) in ai.analysis.iter() {
+                    print_with_delay(
+                        &format!(
+                            " 𝔞𝔦 \x1b[1m{}\x1b[0m {}",
+                            dish_item
+                                .get_dish(dish_id)
+                                .map(|d| d.name.as_str())
+                                .unwrap_or("unknown"),
+                            analysis
+                        ),
+                        1,
+                    )
+                    .await;
+                }
+                println!();
+                print_with_delay(&format!(" 𝔞𝔦 {}", ai.reason), 1).await;
+                let picked = &dish_item.options()[ai_selected].name;
+                if ai_pick.is_some() && ai.repeat_unavoidable {
+                    print_with_delay(
+                        &format!(" 𝔞𝔦 repeating \x1b[1m{picked}\x1b[0m, no fresh option available"),
+                        1,
+                    )
+                    .await;
+                }
+                for (dish_id, why) in ai.comparison.iter() {
+                    // without a valid pick there is nothing to compare against
+                    if ai_pick.is_none() || dish_id == &ai.dish_id || why.is_empty() {
+                        continue;
+                    }
+                    let Some(other) = dish_item.get_dish(dish_id) else {
+                        continue;
+                    };
+                    print_with_delay(
+                        &format!(
+                            " 𝔞𝔦 \x1b[1m{}\x1b[0m chosen over \x1b[1m{}\x1b[0m because {}",
+                            picked, other.name, why
+                        ),
+                        1,
+                    )
+                    .await;
+                }
+            }
+            let names = dish_item
+                .options()
+                .iter()
+                .map(|x| match x.banned(banned) {
+                    Some(banned) => format!("{} \x1b[31m({banned})\x1b[0m", x.label()),
+                    None => x.label(),
+                })
+                .collect::<Vec<_>>();
+            let theme = ColorfulTheme::default();
+            let selection = if config::get().fuzzy_select {
+                FuzzySelect::with_theme(&theme)
+                    .with_prompt(dish_item.display_name())
+                    .items(&names)
+                    .default(ai_selected)
+                    .interact()?
+            } else {
+                Select::with_theme(&theme)
+                    .with_prompt(dish_item.display_name())
+                    .items(&names)
+                    .default(ai_selected)
+                    .interact()?
+            };
+
+            if ai_pick.is_some() && selection != ai_selected {
+                let explaination = ask_reason()?;
+                if !explaination.is_empty() {
+                    user_reason = Some(explaination.clone());
+                }
+                new_preferences.push(UserAdjustment {
+                    from: dish_item.options()[ai_selected].name.clone(),
+                    to: dish_item.options()[selection].name.clone(),
+                    tags: tags::extract_tags(&explaination),
+                    reason: if explaination.is_empty() {
+                        None
+                    } else {
+                        Some(explaination)
+                    },
+                    date: *date,
+                });
+            }
+            if track_progress {
+                Preferences::save_day_progress(
+                    *date,
+                    &dish_item.id,
+                    &dish_item.options()[selection].dish.id,
+                );
+            }
+            selection
+        };
+
+        if Some(selection) != current {
+            menu_changes.items.push(ChangeMenuItem {
+                dish: dish_item.options()[selection].dish.id.clone(),
+                dish_item: dish_item.id.clone(),
+            });
         }
+        let dish = dish_item.options()[selection];
+        decisions.push(Decision {
+            date: *date,
+            dish_item: dish_item.id.clone(),
+            meal_type: dish_item.meal_type.name.clone(),
+            dish_id: dish.dish.id.clone(),
+            dish_name: dish.name.clone(),
+            accepted: ai_pick == Some(selection),
+            ai_reason: ai.map(|ai| ai.reason.clone()),
+            user_reason,
+            heuristic: ai_result.heuristic,
+            saved: false,
+        });
         println!();
-        print_with_delay(&format!(" 𝔞𝔦 {}", ai.reason), 1).await;
-        let selection = Select::with_theme(&ColorfulTheme::default())
-            .with_prompt(dish_item.meal_type.name.to_string())
-            .items(
-                &dish_item
-                    .options()
+    }
+    Ok(new_preferences)
+}
+
+/// Asks why the AI pick was overridden, an empty or aborted answer means no reason
+fn ask_reason() -> eyre::Result<String> {
+    if !config::get().reason_editor {
+        return Ok(Input::new()
+            .with_prompt("Why?")
+            .allow_empty(true)
+            .interact_text()?);
+    }
+    println!("Why? (opening editor, leave empty for no reason)");
+    let reason = Editor::new().edit("")?.unwrap_or_default();
+    Ok(reason.trim().to_string())
+}
+
+fn cache_info() -> eyre::Result<()> {
+    for cache in Cache::ALL {
+        match cache.info()? {
+            Some((entries, bytes)) => println!(
+                "{}: {} entries, {:.1} KiB",
+                cache.name(),
+                entries,
+                bytes as f64 / 1024.0
+            ),
+            None => println!("{}: empty", cache.name()),
+        }
+    }
+    Ok(())
+}
+
+fn clear_caches(ingredients: bool, translations: bool, all: bool) -> eyre::Result<()> {
+    for cache in Cache::ALL {
+        let selected = all
+            || match cache {
+                Cache::Ingredients => ingredients,
+                Cache::Translations => translations,
+            };
+        if selected && cache.clear()? {
+            println!("Cleared {}", cache.name());
+        }
+    }
+    Ok(())
+}
+
+fn validate_preferences() -> eyre::Result<()> {
+    let issues = preferences::issues(
+        &Preferences::get_preferences(),
+        &config::get().flavor_profiles,
+    );
+    if issues.is_empty() {
+        println!("No issues found");
+    }
+    for issue in issues {
+        println!("\x1b[33m{issue}\x1b[0m");
+    }
+    Ok(())
+}
+
+fn list_preferences() -> eyre::Result<()> {
+    let preferences = Preferences::get_preferences();
+    if preferences.is_empty() {
+        println!("No preferences saved");
+    }
+    for (idx, pref) in preferences.iter().enumerate() {
+        println!(
+            "{:>3}. {} \x1b[1m{}\x1b[0m -> \x1b[1m{}\x1b[0m",
+            idx + 1,
+            pref.date,
+            pref.from,
+            pref.to
+        );
+        if let Some(reason) = &pref.reason {
+            println!("     because {reason}");
+        }
+    }
+    Ok(())
+}
+
+/// Removes preferences by the 1-based numbers shown by `prefs list`
+fn remove_preferences(numbers: &[usize]) -> eyre::Result<()> {
+    let count = Preferences::get_preferences().len();
+    if let Some(number) = numbers.iter().find(|&&n| n == 0 || n > count) {
+        eyre::bail!("No preference number {number}, there are {count}");
+    }
+    let mut numbers = numbers.to_vec();
+    // from the end, so earlier numbers still point at the same preferences
+    numbers.sort_unstable_by(|a, b| b.cmp(a));
+    numbers.dedup();
+    for number in numbers {
+        if let Some(pref) = Preferences::remove_preference(number - 1) {
+            println!("Removed {number}. {} -> {}", pref.from, pref.to);
+        }
+    }
+    Ok(())
+}
+
+fn undo_preferences() -> eyre::Result<()> {
+    let removed = Preferences::undo_last_batch();
+    if removed.is_empty() {
+        println!("Nothing to undo");
+    } else {
+        println!("Removed preferences:");
+        for pref in &removed {
+            println!("  {} -> {}", pref.from, pref.to);
+        }
+    }
+    Ok(())
+}
+
+/// Guided setup of the refresh token and the main `config.toml` settings,
+/// settings not asked about keep their current values
+async fn setup() -> eyre::Result<()> {
+    let mut config = Config::load()?;
+    let theme = ColorfulTheme::default();
+    println!(
+        "Setting up, answers are saved to {}",
+        config::config_path().display()
+    );
+
+    if Preferences::token().is_none()
+        || dialoguer::Confirm::with_theme(&theme)
+            .with_prompt("Replace the stored refresh token?")
+            .default(false)
+            .interact()?
+    {
+        update_token().await?;
+    }
+
+    config.model = Input::with_theme(&theme)
+        .with_prompt("OpenAI model")
+        .default(config.model.clone())
+        .interact_text()?;
+
+    let objectives = [
+        (Objective::Taste, "Taste only"),
+        (Objective::TopRated, "Taste, then best rated"),
+        (Objective::Cheapest, "Taste, then cheapest"),
+        (Objective::HighProtein, "Taste, then most protein"),
+    ];
+    let objective = Select::with_theme(&theme)
+        .with_prompt("What should the AI optimize for?")
+        .items(&objectives.map(|(_, label)| label))
+        .default(
+            objectives
+                .iter()
+                .position(|(objective, _)| *objective == config.objective)
+                .unwrap_or(0),
+        )
+        .interact()?;
+    config.objective = objectives[objective].0;
+
+    config.cuisine_preferences = list_input(
+        &theme,
+        "Cuisines you like, comma separated (e.g. Asian, Mediterranean)",
+        &config.cuisine_preferences,
+    )?;
+    config.flavor_profiles = list_input(
+        &theme,
+        "Likes and dislikes, comma separated (e.g. spicy, no mushrooms)",
+        &config.flavor_profiles,
+    )?;
+    config.banned_ingredients = list_input(
+        &theme,
+        "Allergies and ingredients to never pick, comma separated (e.g. peanuts, shrimp)",
+        &config.banned_ingredients,
+    )?;
+    config.variety_weight = Input::with_theme(&theme)
+        .with_prompt("Variety, from 0 (stick to what you like) to 1 (try new dishes)")
+        .default(config.variety_weight)
+        .validate_with(|weight: &f32| {
+            if (0.0..=1.0).contains(weight) {
+                Ok(())
+            } else {
+                Err("must be between 0 and 1")
+            }
+        })
+        .interact_text()?;
+    config.fuzzy_select = dialoguer::Confirm::with_theme(&theme)
+        .with_prompt("Pick dishes by typing part of the name?")
+        .default(config.fuzzy_select)
+        .interact()?;
+
+    config.save()?;
+    println!(
+        "Saved to {}, edit it for more settings",
+        config::config_path().display()
+    );
+    Ok(())
+}
+
+fn list_input(
+    theme: &ColorfulTheme,
+    prompt: &str,
+    current: &[String],
+) -> eyre::Result<Vec<String>> {
+    let text: String = Input::with_theme(theme)
+        .with_prompt(prompt)
+        .default(current.join(", "))
+        .allow_empty(true)
+        .interact_text()?;
+    Ok(text
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Caches ingredients of the selectable days and the history days the AI will see for them
+async fn warm_cache() -> eyre::Result<()> {
+    let token = login().await?;
+    let diets = fetch_diets(&token).await.wrap_err("fetch diets")?;
+    let upcoming = days_available_to_select(&token, &diets).await?.days;
+    let (Some(first), Some(last)) = (upcoming.first(), upcoming.last()) else {
+        clear_status();
+        println!("No days available to select menu");
+        return Ok(());
+    };
+    let days = (-config::get().history_days..=(*last - *first).num_days())
+        .map(|offset| *first + chrono::Duration::days(offset))
+        .collect::<Vec<_>>();
+    let fetched = warm_ingredients_cache(&token, &diets, &days).await?;
+    clear_status();
+    println!("Fetched ingredients of {fetched} dishes");
+    Ok(())
+}
+
+/// Compares the AI's picks of the two most recent runs on the days both covered
+fn diff_runs() -> eyre::Result<()> {
+    let [previous, latest]: [runs::RunPlan; 2] = runs::load_latest(2)?
+        .try_into()
+        .map_err(|_| eyre::eyre!("Need two saved runs to compare"))?;
+    println!(
+        "Comparing the run of {} with the run of {}",
+        previous.finished.format("%Y-%m-%d %H:%M"),
+        latest.finished.format("%Y-%m-%d %H:%M")
+    );
+    // by dish item, days may offer the same meal type more than once
+    let before = previous
+        .picks
+        .iter()
+        .map(|pick| (pick.meal(), pick))
+        .collect::<HashMap<_, _>>();
+    let mut compared = 0;
+    let mut changed = 0;
+    for pick in &latest.picks {
+        let Some(old) = before.get(&pick.meal()) else {
+            continue;
+        };
+        compared += 1;
+        if old.dish_id != pick.dish_id {
+            changed += 1;
+            println!(
+                "{} \x1b[1m{}\x1b[0m\n  \x1b[31m{}\x1b[0m -> \x1b[32m{}\x1b[0m",
+                pick.date, pick.meal_type, old.dish_name, pick.dish_name
+            );
+        }
+    }
+    if compared == 0 {
+        println!("The runs have no meals in common");
+    } else {
+        println!("{changed} of {compared} picks changed");
+    }
+    Ok(())
+}
+
+/// Compares each day of the week with `nutrition_goals` and asks the AI for swaps that get closer
+async fn review_nutrition(date: Option<NaiveDate>) -> eyre::Result<()> {
+    let Some(goals) = config::get().nutrition_goals else {
+        eyre::bail!(
+            "No nutrition_goals set, add them to {}",
+            config::config_path().display()
+        );
+    };
+    ai::api_key()?;
+    let token = login().await?;
+    let diets = fetch_diets(&token).await.wrap_err("fetch diets")?;
+    let date = date.unwrap_or_else(|| Local::now().date_naive());
+    let monday = date - Days::new(date.weekday().num_days_from_monday().into());
+    let start = Local.from_local_datetime(&monday.into()).unwrap();
+    let selectable = days_available_to_select_from(&token, &diets, start)
+        .await?
+        .days
+        .into_iter()
+        .map(|day| day.date_naive())
+        .collect::<Vec<_>>();
+
+    let mut days = Vec::new();
+    let mut week = Nutrition::default();
+    for offset in 0..7 {
+        let day = start + chrono::Duration::days(offset);
+        if diets.is_delivery_gap(day.date_naive()) {
+            continue;
+        }
+        status(&format!("Fetching menu for {}...", day.format("%Y-%m-%d")));
+        let Some(diet) = diet_for_date(&token, &diets, &day).await? else {
+            continue;
+        };
+        let calendar_day_items = get_diet(&day, diet.id, &token)
+            .await
+            .wrap_err("getting diet")?;
+        clear_status();
+        let Some(totals) = calendar_day_items.selected_nutrition() else {
+            println!("{}: no nutrition data", day.format("%Y-%m-%d %A"));
+            continue;
+        };
+        println!("{}: {totals}", day.format("%Y-%m-%d %A"));
+        week += totals;
+        let members = &calendar_day_items.diet_elements.members;
+        days.push(ai::NutritionDay {
+            date: day.date_naive(),
+            selected: members
+                .iter()
+                .filter_map(|dish_item| {
+                    let dish = dish_item.get_selected_option()?;
+                    Some((dish_item.meal_type.name.clone(), dish.name.clone()))
+                })
+                .collect(),
+            totals,
+            options: selectable.contains(&day.date_naive()).then(|| {
+                members
                     .iter()
-                    .map(|x| x.name.as_str())
-                    .collect::<Vec<_>>(),
+                    .filter(|dish_item| !dish_item.is_locked())
+                    .map(|dish_item| {
+                        let options = dish_item
+                            .options()
+                            .iter()
+                            .map(|option| ai::NutritionOption {
+                                name: option.name.clone(),
+                                nutrition: option.nutrition(),
+                            })
+                            .collect();
+                        (dish_item.meal_type.name.clone(), options)
+                    })
+                    .collect()
+            }),
+        });
+    }
+    clear_status();
+    if days.is_empty() {
+        println!("No nutrition data on the menus of the week of {monday}");
+        return Ok(());
+    }
+    println!();
+    println!("Week ({} days): {week}", days.len());
+    println!("Goals:          {}", goals * days.len() as f64);
+    println!();
+
+    status("Ai is looking for swaps...");
+    let suggestions = ai::suggest_nutrition_swaps(&ai::NutritionReview {
+        daily_goals: goals,
+        days,
+    })
+    .await?;
+    clear_status();
+    println!("{suggestions}");
+    Ok(())
+}
+
+/// Describes how a recorded day's selection deviates from the selections since `since`
+async fn compare_with_usual(date: Option<NaiveDate>, since: NaiveDate) -> eyre::Result<()> {
+    ai::api_key()?;
+    let records = history::load(since.min(date.unwrap_or(since)))?;
+    let Some(date) = date.or_else(|| records.iter().map(|record| record.date).max()) else {
+        println!("No menu history recorded since {since}");
+        return Ok(());
+    };
+    let (day, usual): (Vec<_>, Vec<_>) =
+        records.into_iter().partition(|record| record.date == date);
+    let usual = usual
+        .into_iter()
+        .filter(|record| record.date >= since)
+        .collect::<Vec<_>>();
+    let plan = day
+        .iter()
+        .filter_map(|record| {
+            Some((
+                record.meal_type.clone(),
+                record.selected_name()?.to_string(),
+            ))
+        })
+        .collect::<IndexMap<_, _>>();
+    if plan.is_empty() {
+        println!("No selection recorded for {date}");
+        return Ok(());
+    }
+    let days = usual
+        .iter()
+        .map(|record| record.date)
+        .collect::<std::collections::HashSet<_>>()
+        .len();
+    if days == 0 {
+        println!("No other days recorded since {since} to compare with");
+        return Ok(());
+    }
+
+    println!("{}, {}", date, date.format("%A"));
+    for (meal_type, dish) in &plan {
+        println!("  \x1b[1m{meal_type}\x1b[0m {dish}");
+    }
+    status("Ai is comparing with your usual selections...");
+    let description = ai::describe_plan_deviation(&ai::PlanComparison {
+        date,
+        plan,
+        usual_selections: history::selection_counts(&usual),
+        days,
+    })
+    .await
+    .wrap_err("comparing with ai")?;
+    clear_status();
+    println!();
+    print_with_delay(&format!(" 𝔞𝔦 {description}"), 1).await;
+    Ok(())
+}
+
+/// Turns past selections that differ from the kitchen's default into
+/// preferences, after the user picks which ones to keep
+async fn learn_preferences(since: NaiveDate) -> eyre::Result<()> {
+    let token = login().await?;
+    let diets = fetch_diets(&token).await.wrap_err("fetch diets")?;
+
+    let mut learned = Vec::new();
+    let days = (Local::now().date_naive() - since).num_days();
+    for i in (1..=days).rev() {
+        let date = Local::now()
+            .checked_sub_signed(chrono::Duration::days(i))
+            .unwrap();
+        status(&format!("Fetching menu for {}...", date.format("%Y-%m-%d")));
+        let Some(diet) = diet_for_date(&token, &diets, &date)
+            .await
+            .wrap_err_with(|| format!("find diet day for {date}"))?
+        else {
+            continue;
+        };
+        let calendar_day_items = get_diet_with_ingredients(&date, diet.id, &token)
+            .await
+            .wrap_err("getting diet with ingredients")?;
+        learned.extend(adjustments_from_selections(
+            date.date_naive(),
+            &calendar_day_items,
+        ));
+    }
+    clear_status();
+
+    if learned.is_empty() {
+        println!("No dishes other than the default were selected since {since}");
+        return Ok(());
+    }
+
+    let labels = learned
+        .iter()
+        .map(|adjustment| {
+            format!(
+                "{}: {} -> {}",
+                adjustment.date, adjustment.from, adjustment.to
             )
-            .default(ai_selected)
-            .interact()?;
+        })
+        .collect::<Vec<_>>();
+    let keep = dialoguer::MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Preferences to learn (space toggles, enter confirms)")
+        .items(&labels)
+        .defaults(&vec![true; labels.len()])
+        .interact()?;
+    if keep.is_empty() {
+        println!("Nothing saved");
+        return Ok(());
+    }
 
-        if selection != ai_selected {
-            let explaination: String = Input::new()
-                .with_prompt("Why?")
-                .allow_empty(true)
-                .interact_text()?;
-            new_preferences.push(UserAdjustment {
-                from: dish_item.options()[ai_selected].name.clone(),
-                to: dish_item.options()[selection].name.clone(),
-                reason: if explaination.is_empty() {
-                    None
-                } else {
-                    Some(explaination)
-                },
-                date: *date,
-            });
+    let mut adjustments = Vec::new();
+    for (index, mut adjustment) in learned.into_iter().enumerate() {
+        if !keep.contains(&index) {
+            continue;
         }
+        let reason: String = Input::new()
+            .with_prompt(format!(
+                "Why {} over {}? (empty to skip)",
+                adjustment.to, adjustment.from
+            ))
+            .allow_empty(true)
+            .interact_text()?;
+        if !reason.is_empty() {
+            adjustment.tags = tags::extract_tags(&reason);
+            adjustment.reason = Some(reason);
+        }
+        adjustments.push(adjustment);
+    }
+    let count = adjustments.len();
+    Preferences::add_new_preferences(adjustments);
+    println!("Learned {count} preferences, `prefs undo` removes them");
+    Ok(())
+}
 
-        let selected_option_id = dish_item
-            .get_selected_option()
-            .map(|x| x.dish.id.clone())
-            .unwrap_or_default();
+/// Runs the AI for the next selectable day offering `from` or `to` with and
+/// without the extra adjustment, and shows where the picks differ
+async fn test_preference(from: &str, to: &str, reason: Option<&str>) -> eyre::Result<()> {
+    ai::api_key()?;
+    let token = login().await?;
+    let diets = fetch_diets(&token).await.wrap_err("fetch diets")?;
+    let days = days_available_to_select(&token, &diets).await?.days;
 
-        let current = dish_item
-            .options()
+    let mentions = |name: &str| {
+        let name = name.to_lowercase();
+        name.contains(&from.to_lowercase()) || name.contains(&to.to_lowercase())
+    };
+    let mut found = None;
+    for date in days {
+        status(&format!(
+            "Looking for the dish on {}...",
+            date.format("%Y-%m-%d")
+        ));
+        let Some(diet) = diet_for_date(&token, &diets, &date)
+            .await
+            .wrap_err_with(|| format!("find diet day for {date}"))?
+        else {
+            continue;
+        };
+        let calendar_day_items = get_diet_with_ingredients(&date, diet.id, &token)
+            .await
+            .wrap_err("getting diet with ingredients")?;
+        let relevant = calendar_day_items
+            .diet_elements
+            .members
             .iter()
-            .position(|x| x.dish.id == selected_option_id)
-            .unwrap();
-        if selection != current {
-            menu_changes.items.push(ChangeMenuItem {
-                dish: dish_item.options()[selection].dish.id.clone(),
-                dish_item: dish_item.id.clone(),
-            });
+            .filter(|dish_item| !dish_item.is_locked())
+            .flat_map(|dish_item| dish_item.options())
+            .any(|option| mentions(&option.name));
+        if relevant {
+            found = Some((date, calendar_day_items));
+            break;
+        }
+    }
+    clear_status();
+    let Some((date, calendar_day_items)) = found else {
+        println!("No selectable day offers a dish matching {from:?} or {to:?}");
+        return Ok(());
+    };
+    println!("{}, {}", date.format("%Y-%m-%d"), date.format("%A"));
+
+    let last_days_choices = fetch_historical_orders(
+        &token,
+        &diets,
+        &date,
+        config::get().history_days,
+        &HashMap::new(),
+    )
+    .await
+    .wrap_err("fetching historical orders")?;
+
+    let saved = Preferences::get_preferences();
+    status("Ai is thinking without the preference...");
+    let before = ai::select_dish_with_preferences(
+        date.date_naive(),
+        &calendar_day_items.diet_elements.members,
+        &last_days_choices,
+        saved,
+    )
+    .await
+    .wrap_err("selecting dish with ai")?;
+
+    let mut with_test = Preferences::get_preferences();
+    with_test.push(UserAdjustment {
+        from: from.to_string(),
+        to: to.to_string(),
+        tags: reason.map(tags::extract_tags).unwrap_or_default(),
+        reason: reason.map(str::to_string),
+        date: date.date_naive(),
+    });
+    status("Ai is thinking with the preference...");
+    let after = ai::select_dish_with_preferences(
+        date.date_naive(),
+        &calendar_day_items.diet_elements.members,
+        &last_days_choices,
+        with_test,
+    )
+    .await
+    .wrap_err("selecting dish with ai")?;
+    clear_status();
+
+    let dish_name = |dish_item: &DishItem, ai: &AiResponse| {
+        ai.selections
+            .get(&dish_item.id)
+            .and_then(|selection| dish_item.get_dish(&selection.dish_id))
+            .map(|dish| dish.name.clone())
+            .unwrap_or_else(|| "unknown".to_string())
+    };
+    let mut changed = false;
+    for dish_item in &calendar_day_items.diet_elements.members {
+        if dish_item.is_locked() {
+            continue;
+        }
+        let before_name = dish_name(dish_item, &before);
+        let after_name = dish_name(dish_item, &after);
+        println!("\x1b[1m{}\x1b[0m", dish_item.meal_type.name);
+        if before_name == after_name {
+            println!("  {}", before_name);
+        } else {
+            changed = true;
+            println!(
+                "  \x1b[31m{}\x1b[0m -> \x1b[32m{}\x1b[0m",
+                before_name, after_name
+            );
         }
+    }
+    println!();
+    if changed {
+        println!("The preference changes the AI's picks");
+    } else {
+        println!("The preference doesn't change the AI's picks for this day");
+    }
+    Ok(())
+}
+
+async fn dump_raw_menu(date: NaiveDate) -> eyre::Result<()> {
+    let token = login().await?;
+    let diets = fetch_diets(&token).await.wrap_err("fetch diets")?;
+    let date = Local.from_local_datetime(&date.into()).unwrap();
+    let diet_id = diet_for_date(&token, &diets, &date)
+        .await
+        .wrap_err_with(|| format!("find diet day for {date}"))?
+        .ok_or_else(|| eyre::eyre!("no diet for date {date}"))?
+        .id;
+    let data = get_diet_raw(&date, diet_id, &token).await?;
+    clear_status();
+    match serde_json::from_str::<serde_json::Value>(&data) {
+        Ok(value) => println!("{}", serde_json::to_string_pretty(&value)?),
+        Err(_) => println!("{}", data),
+    }
+    Ok(())
+}
+
+/// Runs the selection flow against saved API responses, without touching the PowerMeal API
+async fn replay(
+    menu: &Path,
+    history: &[PathBuf],
+    ingredients: Option<&Path>,
+    date: NaiveDate,
+    stub_ai: bool,
+) -> eyre::Result<()> {
+    if !stub_ai {
+        ai::api_key()?;
+    }
+
+    let ingredients = match ingredients {
+        Some(path) => read_fixture::<DishIngredients>(path)?.members,
+        None => Vec::new(),
+    };
+
+    let mut calendar_day_items: CalendarDayItems = read_fixture(menu)?;
+    calendar_day_items.attach_ingredients(&ingredients);
+
+    let mut last_days_choices = IndexMap::new();
+    for (idx, path) in history.iter().enumerate() {
+        let mut day_items: CalendarDayItems = read_fixture(path)?;
+        day_items.attach_ingredients(&ingredients);
+        let days_ago = (history.len() - idx) as i64;
+        let day = date - chrono::Duration::days(days_ago);
+        insert_history_day(
+            &mut last_days_choices,
+            history_label(day, days_ago),
+            day,
+            day_items,
+        );
+    }
+
+    println!("{}, {}", date.format("%Y-%m-%d"), date.format("%A"));
+    println!("{}", calendar_day_items.debug_options());
+
+    let result = if stub_ai {
+        ai::stub_selection(&calendar_day_items.diet_elements.members)
+    } else {
+        status("Ai is thinking...");
+        let result = ai::select_dish(
+            date,
+            &calendar_day_items.diet_elements.members,
+            &last_days_choices,
+        )
+        .await
+        .wrap_err("selecting dish with ai")?;
+        clear_status();
+        result
+    };
+    println!();
+
+    for reason in &result.reasoning {
+        print_with_delay(&format!(" 𝔞𝔦 {}", reason), 1).await;
+    }
+
+    let mut menu_changes = ChangeMenuRequest::default();
+    let new_preferences = select_dishes(
+        &calendar_day_items,
+        &date,
+        result,
+        &mut menu_changes,
+        &mut Vec::new(),
+        false,
+    )
+    .await
+    .wrap_err("while asking user")?;
+
+    // Nothing is saved in replay mode, only show what would happen
+    if !new_preferences.is_empty() {
+        print_preferences(&new_preferences);
         println!();
     }
-    Ok(new_preferences)
+    if !menu_changes.items.is_empty() {
+        print_menu_changes(&menu_changes, &calendar_day_items)?;
+    }
+    Ok(())
+}
+
+fn read_fixture<T: ::serde::de::DeserializeOwned>(path: &Path) -> eyre::Result<T> {
+    let data =
+        std::fs::read_to_string(path).wrap_err_with(|| format!("reading {}", path.display()))?;
+    serde_json::from_str(&data).wrap_err_with(|| format!("parsing {}", path.display()))
+}
+
+fn show_decisions(since: NaiveDate) -> eyre::Result<()> {
+    let decisions = decisions::load(since)?;
+    if decisions.is_empty() {
+        println!("No decisions recorded since {since}");
+        return Ok(());
+    }
+    let mut last_date = None;
+    for decision in decisions {
+        if last_date != Some(decision.date) {
+            println!("{}, {}", decision.date, decision.date.format("%A"));
+            last_date = Some(decision.date);
+        }
+        println!(
+            "  \x1b[1m{}\x1b[0m {}{}",
+            decision.meal_type,
+            decision.dish_name,
+            if decision.accepted {
+                ""
+            } else {
+                " (your choice)"
+            }
+        );
+        if !decision.saved {
+            println!("    \x1b[33mnot saved\x1b[0m");
+        }
+        if let Some(reason) = &decision.user_reason {
+            println!("    you: {reason}");
+        }
+        if let Some(reason) = &decision.ai_reason {
+            if decision.heuristic {
+                println!("    heuristic: {reason}");
+            } else {
+                println!("    𝔞𝔦 {reason}");
+            }
+        }
+    }
+    Ok(())
 }
 
-async fn _dish_stats() -> eyre::Result<()> {
-    let token = refresh_token(&Preferences::token().unwrap()).await?.token;
+async fn dish_stats(since: NaiveDate) -> eyre::Result<()> {
+    let records = history::load(since)?;
+    if records.is_empty() {
+        println!("No menu history recorded since {since}, fetching menus");
+        return fetch_dish_stats(since).await;
+    }
+
+    // dish id -> (name, times offered, times selected)
+    let mut dish_counts: IndexMap<String, (String, usize, usize)> = IndexMap::new();
+    for record in &records {
+        for dish in &record.offered {
+            dish_counts
+                .entry(dish.id.clone())
+                .or_insert_with(|| (dish.name.clone(), 0, 0))
+                .1 += 1;
+        }
+        if let (Some(selected), Some(name)) = (&record.selected, record.selected_name()) {
+            dish_counts
+                .entry(selected.clone())
+                .or_insert_with(|| (name.to_string(), 0, 0))
+                .2 += 1;
+        }
+    }
+
+    dish_counts.sort_by(|_, a, _, b| b.2.cmp(&a.2).then(b.1.cmp(&a.1)));
+    for (dish, (name, offered, selected)) in dish_counts {
+        println!(
+            "{} [id={}] : offered {}, selected {}",
+            name, dish, offered, selected
+        );
+    }
+    Ok(())
+}
+
+async fn fetch_dish_stats(since: NaiveDate) -> eyre::Result<()> {
+    let token = login().await?;
     let diets = fetch_diets(&token).await?;
 
     // Map to store dish counts
     let mut dish_counts = std::collections::HashMap::new();
     let mut dish_names = std::collections::HashMap::new();
-    // Iterate over last 30 days
-    for i in 0..30 {
+    // Iterate over every day since the requested date
+    let days = (Local::now().date_naive() - since).num_days();
+    for i in 0..=days {
         let date = chrono::Local::now()
             .checked_sub_signed(chrono::Duration::days(i))
             .unwrap();
-        let diet_id = diets.diet_for_date(&date).wrap_err("no diet for date")?.id;
-        let calendar_day_items = get_diet_with_ingredients(&date, diet_id, &token).await?;
+        // paused subscriptions and delivery gaps have nothing to count
+        let Some(diet) = diet_for_date(&token, &diets, &date)
+            .await
+            .wrap_err_with(|| format!("find diet day for {date}"))?
+        else {
+            continue;
+        };
+        // only dish ids are counted, ingredients aren't needed
+        let calendar_day_items = get_diet(&date, diet.id, &token)
+            .await
+            .wrap_err("getting diet")?;
 
         // Count dishes
         for dish in calendar_day_items
@@ -517,7 +2471,7 @@ fn init_tracing() {
         .with(
             tracing_subscriber::fmt::layer().with_filter(
                 tracing_subscriber::EnvFilter::try_from_default_env()
-                    .unwrap_or_else(|_| "powermeal-ai-choice=info".into()),
+                    .unwrap_or_else(|_| "powermeal_ai_choice=info".into()),
             ),
         )
         .init();
@@ -0,0 +1,49 @@
+use chrono::NaiveDate;
+use eyre::Context;
+use serde::Serialize;
+
+use crate::runs::AiPick;
+
+/// Outcome of a single run, sent to the notification webhook
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    pub days_processed: Vec<NaiveDate>,
+    pub changes_made: usize,
+    /// Meals the AI picked a dish for
+    pub ai_picks: usize,
+    /// AI picks that were the dish already selected by the kitchen
+    pub ai_kept_default: usize,
+    /// Meals picked by the local heuristic instead of the AI
+    pub heuristic_picks: usize,
+    /// Surcharges of the dishes selected on processed days, when the menu has prices
+    pub surcharges: f64,
+    pub errors: Vec<String>,
+    /// What the AI proposed, saved for `diff-runs` rather than sent
+    #[serde(skip)]
+    pub ai_plan: Vec<AiPick>,
+}
+
+impl RunSummary {
+    pub fn record_ai_picks(&mut self, picks: usize, changes: usize, heuristic: bool) {
+        if heuristic {
+            self.heuristic_picks += picks;
+            return;
+        }
+        self.ai_picks += picks;
+        self.ai_kept_default += picks.saturating_sub(changes);
+    }
+}
+
+pub async fn send_webhook(url: &str, summary: &RunSummary) -> eyre::Result<()> {
+    let body = serde_json::to_string(summary).wrap_err("while serializing run summary")?;
+    reqwest::Client::new()
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .await
+        .wrap_err("in http request")?
+        .error_for_status()
+        .wrap_err("webhook rejected notification")?;
+    Ok(())
+}
@@ -1,22 +1,50 @@
 use std::path::PathBuf;
 
 use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
-use crate::ai::UserAdjustment;
+use crate::{ai::UserAdjustment, recording, ChangeMenuRequest};
 
-const PREFERENCES_FILE: &str = ".config/powermeal-ai/preferences.json";
+const CONFIG_DIR: &str = ".config/powermeal-ai";
+const PREFERENCES_FILE: &str = "preferences.json";
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Preferences {
     adjustments: Vec<UserAdjustment>,
     last_day_selected: Option<NaiveDate>,
     token: Option<String>,
+    #[serde(default)]
+    day_progress: Option<DayProgress>,
+    /// Number of adjustments added by the last `add_new_preferences` call
+    #[serde(default)]
+    last_batch_len: usize,
+    /// Days excluded with `skip-date`, on top of `exclude_dates` from the config
+    #[serde(default)]
+    excluded_dates: Vec<NaiveDate>,
+    /// Menu fingerprints of days already selected, to skip them while the menu stays the same
+    #[serde(default)]
+    day_fingerprints: IndexMap<NaiveDate, String>,
+    /// Days seen without a published menu yet, checked again until they become selectable
+    #[serde(default)]
+    pending_menu_dates: Vec<NaiveDate>,
+    /// Planned menu changes that failed to save, submitted again by the next plan
+    #[serde(default)]
+    unsaved_changes: IndexMap<NaiveDate, ChangeMenuRequest>,
+}
+
+/// Selections made for a day that was not finished yet, so an interrupted run can resume it
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DayProgress {
+    pub date: NaiveDate,
+    /// dish item id -> selected dish id
+    pub selections: IndexMap<String, String>,
 }
 
 impl Preferences {
     pub fn add_new_preferences(adjustment: Vec<UserAdjustment>) {
         let mut preferences = Self::load_preferences();
+        preferences.last_batch_len = adjustment.len();
         preferences.adjustments.extend(adjustment);
         // limit to last 100 adjustments
         if preferences.adjustments.len() > 100 {
@@ -27,10 +55,100 @@ impl Preferences {
         preferences.save_preferences();
     }
 
+    /// Removes adjustments added by the most recent `add_new_preferences` call
+    pub fn undo_last_batch() -> Vec<UserAdjustment> {
+        let mut preferences = Self::load_preferences();
+        // new adjustments are appended and trimming removes from the front,
+        // so the last batch is always at the end
+        let batch_start = preferences
+            .adjustments
+            .len()
+            .saturating_sub(preferences.last_batch_len);
+        let removed = preferences.adjustments.split_off(batch_start);
+        preferences.last_batch_len = 0;
+        preferences.save_preferences();
+        removed
+    }
+
+    /// Removes the adjustment at `index`, None when there is none
+    pub fn remove_preference(index: usize) -> Option<UserAdjustment> {
+        let mut preferences = Self::load_preferences();
+        if index >= preferences.adjustments.len() {
+            return None;
+        }
+        // keep undo limited to what is left of the last batch
+        let batch_start = preferences
+            .adjustments
+            .len()
+            .saturating_sub(preferences.last_batch_len);
+        if index >= batch_start {
+            preferences.last_batch_len = preferences.last_batch_len.saturating_sub(1);
+        }
+        let removed = preferences.adjustments.remove(index);
+        preferences.save_preferences();
+        Some(removed)
+    }
+
     pub fn get_preferences() -> Vec<UserAdjustment> {
         Self::load_preferences().adjustments
     }
 
+    pub fn exclude_date(date: NaiveDate) {
+        let mut preferences = Self::load_preferences();
+        if !preferences.excluded_dates.contains(&date) {
+            preferences.excluded_dates.push(date);
+            preferences.excluded_dates.sort_unstable();
+        }
+        preferences.save_preferences();
+    }
+
+    /// Excluded days that are still ahead, past ones are dropped
+    pub fn excluded_dates() -> Vec<NaiveDate> {
+        let mut preferences = Self::load_preferences();
+        let today = Local::now().date_naive();
+        let before = preferences.excluded_dates.len();
+        preferences.excluded_dates.retain(|date| *date >= today);
+        let excluded_dates = preferences.excluded_dates.clone();
+        if excluded_dates.len() != before {
+            preferences.save_preferences();
+        }
+        excluded_dates
+    }
+
+    /// Days still waiting for their menu, past ones are dropped
+    pub fn pending_menu_dates() -> Vec<NaiveDate> {
+        let today = Local::now().date_naive();
+        Self::load_preferences()
+            .pending_menu_dates
+            .into_iter()
+            .filter(|date| *date >= today)
+            .collect()
+    }
+
+    pub fn set_pending_menu_dates(mut dates: Vec<NaiveDate>) {
+        let mut preferences = Self::load_preferences();
+        dates.sort_unstable();
+        if preferences.pending_menu_dates != dates {
+            preferences.pending_menu_dates = dates;
+            preferences.save_preferences();
+        }
+    }
+
+    /// Menu fingerprints of selected days, by date
+    pub fn day_fingerprints() -> IndexMap<NaiveDate, String> {
+        Self::load_preferences().day_fingerprints
+    }
+
+    pub fn save_day_fingerprint(date: NaiveDate, fingerprint: String) {
+        let mut preferences = Self::load_preferences();
+        let today = Local::now().date_naive();
+        preferences
+            .day_fingerprints
+            .retain(|date, _| *date >= today);
+        preferences.day_fingerprints.insert(date, fingerprint);
+        preferences.save_preferences();
+    }
+
     pub fn next_day_to_check() -> Option<DateTime<Local>> {
         Self::load_preferences()
             .last_day_selected
@@ -39,7 +157,60 @@ impl Preferences {
 
     pub fn set_next_day_to_check(date: NaiveDate) {
         let mut preferences = Self::load_preferences();
-        preferences.last_day_selected = Some(date);
+        // A day whose menu was published late may be selected after later days were
+        preferences.last_day_selected = preferences.last_day_selected.max(Some(date));
+        preferences.save_preferences();
+    }
+
+    pub fn day_progress(date: &NaiveDate) -> Option<DayProgress> {
+        Self::load_preferences()
+            .day_progress
+            .filter(|progress| &progress.date == date)
+    }
+
+    pub fn save_day_progress(date: NaiveDate, dish_item_id: &str, dish_id: &str) {
+        let mut preferences = Self::load_preferences();
+        let mut progress = preferences
+            .day_progress
+            .take()
+            .filter(|progress| progress.date == date)
+            .unwrap_or_else(|| DayProgress {
+                date,
+                selections: IndexMap::new(),
+            });
+        progress
+            .selections
+            .insert(dish_item_id.to_string(), dish_id.to_string());
+        preferences.day_progress = Some(progress);
+        preferences.save_preferences();
+    }
+
+    /// Planned changes of days that failed to save, past days are dropped
+    pub fn unsaved_changes() -> IndexMap<NaiveDate, ChangeMenuRequest> {
+        let today = Local::now().date_naive();
+        let mut unsaved_changes = Self::load_preferences().unsaved_changes;
+        unsaved_changes.retain(|date, _| *date >= today);
+        unsaved_changes
+    }
+
+    pub fn save_unsaved_changes(date: NaiveDate, menu_changes: &ChangeMenuRequest) {
+        let mut preferences = Self::load_preferences();
+        preferences
+            .unsaved_changes
+            .insert(date, menu_changes.clone());
+        preferences.save_preferences();
+    }
+
+    pub fn clear_unsaved_changes(date: NaiveDate) {
+        let mut preferences = Self::load_preferences();
+        if preferences.unsaved_changes.shift_remove(&date).is_some() {
+            preferences.save_preferences();
+        }
+    }
+
+    pub fn clear_day_progress() {
+        let mut preferences = Self::load_preferences();
+        preferences.day_progress = None;
         preferences.save_preferences();
     }
 
@@ -51,25 +222,41 @@ impl Preferences {
             let preferences: Preferences = serde_json::from_reader(reader).unwrap();
             preferences
         } else {
-            Preferences {
-                adjustments: Vec::new(),
-                last_day_selected: None,
-                token: None,
-            }
+            Preferences::default()
         }
     }
 
+    /// Keeps the refresh token in the OS secret store when built with the `keyring`
+    /// feature, in preferences.json otherwise or when no secret store is available
     pub fn save_token(token: &str) {
         let mut preferences = Self::load_preferences();
+        #[cfg(feature = "keyring")]
+        if secret_store::save_token(token) {
+            // the plaintext copy of an earlier token is no longer needed
+            if preferences.token.take().is_some() {
+                preferences.save_preferences();
+            }
+            return;
+        }
+        #[cfg(feature = "keyring")]
+        secret_store::delete_token();
         preferences.token = Some(token.to_string());
         preferences.save_preferences();
     }
 
     pub fn token() -> Option<String> {
+        #[cfg(feature = "keyring")]
+        if let Some(token) = secret_store::token() {
+            return Some(token);
+        }
         Self::load_preferences().token
     }
 
     fn save_preferences(self) {
+        // a replayed run must leave what the user learned and selected as it was
+        if recording::replaying() {
+            return;
+        }
         let path = Self::config_path();
         if !path.exists() {
             std::fs::create_dir_all(path.parent().unwrap()).expect("Failed to create directory");
@@ -80,10 +267,111 @@ impl Preferences {
     }
 
     fn config_path() -> PathBuf {
-        std::env::var("HOME")
-            .expect("HOME not set")
-            .parse::<PathBuf>()
-            .expect("invalid HOME")
-            .join(PREFERENCES_FILE)
+        config_dir().join(PREFERENCES_FILE)
+    }
+}
+
+/// Form used to compare dish names and profile entries, ignoring case and spacing
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Problems with saved preferences and the config that would confuse the AI
+pub fn issues(adjustments: &[UserAdjustment], flavor_profiles: &[String]) -> Vec<String> {
+    let mut issues = Vec::new();
+    let mut seen = IndexMap::<(String, String), usize>::new();
+    for adjustment in adjustments {
+        *seen
+            .entry((normalize(&adjustment.from), normalize(&adjustment.to)))
+            .or_default() += 1;
+        if normalize(&adjustment.from) == normalize(&adjustment.to) {
+            issues.push(format!(
+                "Preference switches {} to itself ({})",
+                adjustment.from, adjustment.date
+            ));
+        }
+    }
+    for ((from, to), count) in &seen {
+        if *count > 1 {
+            issues.push(format!("Preference {from} -> {to} is saved {count} times"));
+        }
+        if from < to && seen.contains_key(&(to.clone(), from.clone())) {
+            issues.push(format!(
+                "Preferences contradict each other: {from} -> {to} and {to} -> {from}"
+            ));
+        }
+    }
+
+    let profiles = flavor_profiles
+        .iter()
+        .map(|profile| normalize(profile))
+        .collect::<Vec<_>>();
+    for profile in &profiles {
+        let Some(avoided) = ["no ", "not ", "without "]
+            .iter()
+            .find_map(|prefix| profile.strip_prefix(prefix))
+        else {
+            continue;
+        };
+        if profiles.iter().any(|liked| liked == avoided) {
+            issues.push(format!("Flavor profiles both ask for and avoid {avoided}"));
+        }
+    }
+    issues
+}
+
+/// Directory holding preferences and everything else the tool stores
+pub fn config_dir() -> PathBuf {
+    std::env::var("HOME")
+        .expect("HOME not set")
+        .parse::<PathBuf>()
+        .expect("invalid HOME")
+        .join(CONFIG_DIR)
+}
+
+/// Refresh token in the OS secret store (Secret Service, macOS Keychain, Windows
+/// Credential Manager)
+#[cfg(feature = "keyring")]
+mod secret_store {
+    const SERVICE: &str = "powermeal-ai";
+    const USER: &str = "refresh-token";
+
+    fn entry() -> keyring::Result<keyring::Entry> {
+        keyring::Entry::new(SERVICE, USER)
+    }
+
+    pub fn token() -> Option<String> {
+        match entry().and_then(|entry| entry.get_password()) {
+            Ok(token) => Some(token),
+            Err(keyring::Error::NoEntry) => None,
+            Err(e) => {
+                tracing::warn!("Can't read the refresh token from the secret store: {e}");
+                None
+            }
+        }
+    }
+
+    /// Whether the token was stored, false when no secret store is available
+    pub fn save_token(token: &str) -> bool {
+        match entry().and_then(|entry| entry.set_password(token)) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!("Can't use the secret store, keeping the refresh token in preferences.json: {e}");
+                false
+            }
+        }
+    }
+
+    /// Removes the stored token, so an older one doesn't shadow the one in preferences.json
+    pub fn delete_token() {
+        match entry().and_then(|entry| entry.delete_credential()) {
+            Ok(()) | Err(keyring::Error::NoEntry) => {}
+            Err(e) => {
+                tracing::warn!("Can't remove the old refresh token from the secret store: {e}")
+            }
+        }
     }
 }
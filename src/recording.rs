@@ -0,0 +1,177 @@
+//! Recording of the API and AI traffic of a run, so a run reported by a user
+//! can be repeated offline. Tokens are redacted before anything is written.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use eyre::Context;
+use serde::{Deserialize, Serialize};
+
+/// JSON fields holding credentials, never written to a recording
+const SECRET_FIELDS: &[&str] = &["token", "refreshToken", "refresh_token"];
+
+enum Mode {
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+static MODE: OnceLock<Mode> = OnceLock::new();
+/// Requests seen so far by key, a repeated request gets the next recorded response
+static SEEN: Mutex<Option<HashMap<String, usize>>> = Mutex::new(None);
+/// Files replayed by `replayed_in_order`, which may take them out of order
+static USED: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Exchange {
+    pub request: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    pub status: u16,
+    pub response: String,
+}
+
+/// Writes every exchange of this run to `dir`
+pub fn record_to(dir: &Path) -> eyre::Result<()> {
+    std::fs::create_dir_all(dir).wrap_err_with(|| format!("creating {}", dir.display()))?;
+    set_mode(Mode::Record(dir.to_path_buf()))
+}
+
+/// Answers every request of this run from the exchanges recorded in `dir`
+pub fn replay_from(dir: &Path) -> eyre::Result<()> {
+    if !dir.is_dir() {
+        eyre::bail!("no recording at {}", dir.display());
+    }
+    set_mode(Mode::Replay(dir.to_path_buf()))
+}
+
+fn set_mode(mode: Mode) -> eyre::Result<()> {
+    MODE.set(mode)
+        .map_err(|_| eyre::eyre!("recording or replay is already set up"))
+}
+
+pub fn replaying() -> bool {
+    matches!(MODE.get(), Some(Mode::Replay(_)))
+}
+
+/// Whether this run is recorded or replayed, so local caches must not stand in for requests
+pub fn active() -> bool {
+    MODE.get().is_some()
+}
+
+/// Recorded exchange for the next request with `key`, None unless replaying
+pub fn replayed(key: &str) -> eyre::Result<Option<Exchange>> {
+    let Some(Mode::Replay(dir)) = MODE.get() else {
+        return Ok(None);
+    };
+    let path = dir.join(file_name(key, next_occurrence(key)));
+    let data = std::fs::read_to_string(&path)
+        .wrap_err_with(|| format!("no recorded response for {key} ({})", path.display()))?;
+    let exchange =
+        serde_json::from_str(&data).wrap_err_with(|| format!("parsing {}", path.display()))?;
+    Ok(Some(exchange))
+}
+
+/// Recorded exchange for a request with `key` whose `body` depends on local settings,
+/// so it rarely matches between machines. Exchanges are taken in the order they were
+/// recorded, except that one recorded for the same body is taken first, as concurrent
+/// requests may finish in another order than when recorded.
+pub fn replayed_in_order(key: &str, body: &str) -> eyre::Result<Option<Exchange>> {
+    let Some(Mode::Replay(dir)) = MODE.get() else {
+        return Ok(None);
+    };
+    let body = redact(body);
+    let mut used = USED.lock().unwrap();
+    let used = used.get_or_insert_with(HashSet::new);
+    let mut next = None;
+    for occurrence in 1.. {
+        let name = file_name(key, occurrence);
+        let path = dir.join(&name);
+        if !path.exists() {
+            break;
+        }
+        if used.contains(&name) {
+            continue;
+        }
+        let data = std::fs::read_to_string(&path)
+            .wrap_err_with(|| format!("reading {}", path.display()))?;
+        let exchange: Exchange =
+            serde_json::from_str(&data).wrap_err_with(|| format!("parsing {}", path.display()))?;
+        if exchange.body.as_deref() == Some(body.as_str()) {
+            next = Some((name, exchange));
+            break;
+        }
+        if next.is_none() {
+            next = Some((name, exchange));
+        }
+    }
+    let Some((name, exchange)) = next else {
+        eyre::bail!("no recorded response left for {key} in {}", dir.display());
+    };
+    used.insert(name);
+    Ok(Some(exchange))
+}
+
+/// Saves an exchange when recording
+pub fn record(key: &str, body: Option<&str>, status: u16, response: &str) -> eyre::Result<()> {
+    let Some(Mode::Record(dir)) = MODE.get() else {
+        return Ok(());
+    };
+    let exchange = Exchange {
+        request: key.to_string(),
+        body: body.map(redact),
+        status,
+        response: redact(response),
+    };
+    let path = dir.join(file_name(key, next_occurrence(key)));
+    std::fs::write(&path, serde_json::to_string_pretty(&exchange)?)
+        .wrap_err_with(|| format!("writing {}", path.display()))
+}
+
+fn next_occurrence(key: &str) -> usize {
+    let mut seen = SEEN.lock().unwrap();
+    let count = seen
+        .get_or_insert_with(HashMap::new)
+        .entry(key.to_string())
+        .or_default();
+    *count += 1;
+    *count
+}
+
+fn file_name(key: &str, occurrence: usize) -> String {
+    let name = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    format!("{name}.{occurrence}.json")
+}
+
+/// Replaces credentials in a JSON text, other non-empty text can't be checked and is dropped
+fn redact(text: &str) -> String {
+    fn redact_value(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(fields) => {
+                for (name, field) in fields.iter_mut() {
+                    if SECRET_FIELDS.contains(&name.as_str()) {
+                        *field = serde_json::Value::String("REDACTED".to_string());
+                    } else {
+                        redact_value(field);
+                    }
+                }
+            }
+            serde_json::Value::Array(items) => items.iter_mut().for_each(redact_value),
+            _ => {}
+        }
+    }
+
+    if text.trim().is_empty() {
+        return text.to_string();
+    }
+    let Ok(mut value) = serde_json::from_str::<serde_json::Value>(text) else {
+        return format!("REDACTED {} bytes of non-JSON text", text.len());
+    };
+    redact_value(&mut value);
+    value.to_string()
+}
@@ -0,0 +1,153 @@
+//! Rules limiting how often dishes of some kind are eaten, like "no red meat
+//! two days in a row". They are checked against the selections of earlier days
+//! before each day is selected.
+
+use chrono::{Datelike, Days, NaiveDate};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{CalendarDayItems, DishItem, MenuDietOption};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// What the matched dishes are, e.g. "red meat"
+    pub name: String,
+    pub kind: RuleKind,
+    /// Parts of dish names or ingredients a dish is matched by, case insensitive
+    pub matches: Vec<String>,
+    /// Limit of `max_per_week` and `at_least_per_week`
+    #[serde(default = "default_count")]
+    pub count: usize,
+}
+
+fn default_count() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleKind {
+    NoConsecutive,
+    MaxPerWeek,
+    AtLeastPerWeek,
+}
+
+/// What a rule demands of the day being selected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Constraint {
+    /// Matching dishes are taken out of the options
+    Forbid,
+    /// At least one meal should match
+    Require,
+}
+
+pub struct RuleCheck<'a> {
+    pub rule: &'a Rule,
+    pub constraint: Option<Constraint>,
+    /// The rule and how it stands, as told to the AI
+    pub description: String,
+}
+
+impl Rule {
+    pub fn matches(&self, option: &MenuDietOption) -> bool {
+        option.find_fragment(&self.matches, false).is_some()
+    }
+
+    fn matched_on(&self, day: &CalendarDayItems) -> bool {
+        day.diet_elements
+            .members
+            .iter()
+            .filter_map(|dish_item| dish_item.get_selected_option())
+            .any(|option| self.matches(option))
+    }
+
+    /// Checks the rule for `date` against the selections of earlier days.
+    /// Weeks start on Monday and only days in `history` are counted.
+    pub fn check(
+        &self,
+        date: NaiveDate,
+        history: &IndexMap<String, CalendarDayItems>,
+    ) -> RuleCheck<'_> {
+        let name = &self.name;
+        let count = self.count;
+        let week_start = date - Days::new(date.weekday().num_days_from_monday().into());
+        let this_week = history
+            .values()
+            .filter(|day| {
+                day.date
+                    .is_some_and(|day_date| week_start <= day_date && day_date < date)
+            })
+            .filter(|day| self.matched_on(day))
+            .count();
+        let (constraint, description) = match self.kind {
+            RuleKind::NoConsecutive => {
+                let yesterday = date.pred_opt();
+                let eaten_yesterday = history
+                    .values()
+                    .any(|day| day.date.is_some() && day.date == yesterday && self.matched_on(day));
+                if eaten_yesterday {
+                    (
+                        Some(Constraint::Forbid),
+                        format!("{name}: not today, it was eaten yesterday"),
+                    )
+                } else {
+                    (None, format!("{name}: never two days in a row"))
+                }
+            }
+            RuleKind::MaxPerWeek if this_week >= count => (
+                Some(Constraint::Forbid),
+                format!("{name}: not today, eaten {this_week} times this week (at most {count})"),
+            ),
+            RuleKind::MaxPerWeek => (
+                None,
+                format!("{name}: at most {count} times a week, {this_week} so far"),
+            ),
+            RuleKind::AtLeastPerWeek => {
+                // today included
+                let days_left = 7 - date.weekday().num_days_from_monday() as usize;
+                if this_week < count && days_left <= count - this_week {
+                    (
+                        Some(Constraint::Require),
+                        format!("{name}: needed today, eaten {this_week} of at least {count} times this week"),
+                    )
+                } else {
+                    (
+                        None,
+                        format!("{name}: at least {count} times a week, {this_week} so far"),
+                    )
+                }
+            }
+        };
+        RuleCheck {
+            rule: self,
+            constraint,
+            description,
+        }
+    }
+}
+
+/// Disables options matching any of `forbidden`, unless that would leave the
+/// meal without options. Returns whether anything was disabled.
+pub fn prune(dish_item: &mut DishItem, forbidden: &[&Rule]) -> bool {
+    let forbidden_option =
+        |option: &MenuDietOption| forbidden.iter().any(|rule| rule.matches(option));
+    let allowed = dish_item
+        .options
+        .iter()
+        .filter(|option| option.enabled && !forbidden_option(option))
+        .count();
+    let pruned = dish_item
+        .options
+        .iter()
+        .any(|option| option.enabled && forbidden_option(option));
+    if allowed == 0 || !pruned {
+        return false;
+    }
+    for option in &mut dish_item.options {
+        if forbidden_option(option) {
+            option.enabled = false;
+        }
+    }
+    true
+}
@@ -0,0 +1,83 @@
+//! Plans the AI proposed in each run, to see how its picks change after
+//! preferences or settings were edited.
+
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::{ai::AiResponse, jsonl, CalendarDayItems};
+
+const RUNS_FILE: &str = "runs.jsonl";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RunPlan {
+    pub finished: DateTime<Local>,
+    pub picks: Vec<AiPick>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AiPick {
+    pub date: NaiveDate,
+    /// Empty in plans saved before it was recorded
+    #[serde(default)]
+    pub dish_item: String,
+    pub meal_type: String,
+    pub dish_id: String,
+    pub dish_name: String,
+    /// Picked by the local heuristic because the AI was not used
+    #[serde(default)]
+    pub heuristic: bool,
+}
+
+/// The AI's pick for every meal of the day it answered for
+pub fn picks(
+    date: NaiveDate,
+    calendar_day_items: &CalendarDayItems,
+    ai_result: &AiResponse,
+) -> Vec<AiPick> {
+    calendar_day_items
+        .diet_elements
+        .members
+        .iter()
+        .filter_map(|dish_item| {
+            let selection = ai_result.selections.get(&dish_item.id)?;
+            let dish = dish_item.get_dish(&selection.dish_id)?;
+            Some(AiPick {
+                date,
+                dish_item: dish_item.id.clone(),
+                meal_type: dish_item.meal_type.name.clone(),
+                dish_id: dish.dish.id.clone(),
+                dish_name: dish.name.clone(),
+                heuristic: ai_result.heuristic,
+            })
+        })
+        .collect()
+}
+
+impl AiPick {
+    /// The meal the pick is for, by meal type in plans saved without the dish item
+    pub fn meal(&self) -> (NaiveDate, &str) {
+        if self.dish_item.is_empty() {
+            (self.date, &self.meal_type)
+        } else {
+            (self.date, &self.dish_item)
+        }
+    }
+}
+
+pub fn save(picks: &[AiPick]) -> eyre::Result<()> {
+    if picks.is_empty() {
+        return Ok(());
+    }
+    let plan = RunPlan {
+        finished: Local::now(),
+        picks: picks.to_vec(),
+    };
+    jsonl::append(RUNS_FILE, &[plan])
+}
+
+/// Up to `count` most recent run plans, oldest first
+pub fn load_latest(count: usize) -> eyre::Result<Vec<RunPlan>> {
+    let mut plans = jsonl::load::<RunPlan>(RUNS_FILE, "run plan")?;
+    let skip = plans.len().saturating_sub(count);
+    Ok(plans.split_off(skip))
+}
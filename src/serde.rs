@@ -1,12 +1,21 @@
 use std::collections::HashMap;
 
-use chrono::{DateTime, FixedOffset, Local, NaiveDate};
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDate, Weekday};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CalendarDayItems {
     #[serde(rename = "dietElements")]
     pub diet_elements: DietElements,
+    /// Fields of the items response we don't model (e.g. add-ons), kept so they can be inspected
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+    /// Diet the items were fetched for, not part of the response
+    #[serde(skip)]
+    pub diet_id: Option<i64>,
+    /// Day the items were fetched for, not part of the response
+    #[serde(skip)]
+    pub date: Option<NaiveDate>,
 }
 
 impl CalendarDayItems {
@@ -24,6 +33,176 @@ impl CalendarDayItems {
             .iter()
             .find(|dish_item| dish_item.id == dish_item_id)
     }
+
+    /// Identifies the offered and selected dishes, to notice when a day's menu or its
+    /// selection changes between runs
+    pub fn fingerprint(&self) -> String {
+        let mut offered = self
+            .diet_elements
+            .members
+            .iter()
+            .map(|dish_item| {
+                let mut dishes = dish_item
+                    .options()
+                    .iter()
+                    .map(|option| option.dish.id.as_str())
+                    .collect::<Vec<_>>();
+                dishes.sort_unstable();
+                format!(
+                    "{}={}>{}",
+                    dish_item.id,
+                    dishes.join(","),
+                    dish_item.dish_size.dish.id
+                )
+            })
+            .collect::<Vec<_>>();
+        offered.sort_unstable();
+        format!("{:016x}", crate::stable_hash(&offered.join(";")))
+    }
+
+    /// Sum of surcharges of the selected dishes, None when the menu has no surcharges
+    pub fn surcharge_total(&self) -> Option<f64> {
+        self.diet_elements
+            .members
+            .iter()
+            .filter_map(|dish_item| dish_item.get_selected_option()?.surcharge())
+            .fold(None, |total, surcharge| {
+                Some(total.unwrap_or(0.0) + surcharge)
+            })
+    }
+
+    /// Locks meals not in `meal_types` so nothing is picked for them, returns
+    /// the names that match no meal of the day
+    pub fn lock_meals_except(&mut self, meal_types: &[String]) -> Vec<String> {
+        // meal names are Polish, eq_ignore_ascii_case would tell "Śniadanie" from "śniadanie"
+        let same = |name: &str, meal_type: &str| name.to_lowercase() == meal_type.to_lowercase();
+        for dish_item in &mut self.diet_elements.members {
+            if !meal_types
+                .iter()
+                .any(|name| same(name, &dish_item.meal_type.name))
+            {
+                dish_item.locked = true;
+            }
+        }
+        meal_types
+            .iter()
+            .filter(|name| {
+                !self
+                    .diet_elements
+                    .members
+                    .iter()
+                    .any(|dish_item| same(name, &dish_item.meal_type.name))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Nutrition of the selected dishes, None unless every one of them has it
+    pub fn selected_nutrition(&self) -> Option<Nutrition> {
+        let mut total = Nutrition::default();
+        for dish_item in &self.diet_elements.members {
+            total += dish_item.get_selected_option()?.nutrition()?;
+        }
+        Some(total)
+    }
+
+    /// Whether no meal of the day can be changed
+    pub fn all_locked(&self) -> bool {
+        self.diet_elements
+            .members
+            .iter()
+            .all(|dish_item| dish_item.is_locked())
+    }
+
+    /// Whether a meal has a dish other than the kitchen's default selected,
+    /// usually because it was picked by hand
+    pub fn has_manual_selection(&self) -> bool {
+        self.diet_elements.members.iter().any(|dish_item| {
+            !dish_item.is_locked()
+                && dish_item.get_selected_option().map(|dish| &dish.dish.id)
+                    != dish_item.default_option().map(|dish| &dish.dish.id)
+        })
+    }
+
+    /// Every option of every meal, to fill in details like ingredients
+    pub fn options_mut(&mut self) -> impl Iterator<Item = &mut MenuDietOption> {
+        self.diet_elements
+            .members
+            .iter_mut()
+            .flat_map(|dish_item| dish_item.options.iter_mut())
+    }
+
+    /// First dish item id listed more than once. Selections and menu changes are
+    /// keyed by it, so the meals sharing it couldn't be told apart.
+    pub fn duplicate_dish_item_id(&self) -> Option<&str> {
+        let members = &self.diet_elements.members;
+        members
+            .iter()
+            .enumerate()
+            .find(|(idx, dish_item)| members[..*idx].iter().any(|other| other.id == dish_item.id))
+            .map(|(_, dish_item)| dish_item.id.as_str())
+    }
+
+    /// Checks that `changes` only pick dishes offered in this menu, and that the
+    /// menu belongs to `diet_id`, before they are submitted to that diet
+    pub fn validate_changes(&self, diet_id: i64, changes: &ChangeMenuRequest) -> eyre::Result<()> {
+        if let Some(fetched_for) = self.diet_id {
+            if fetched_for != diet_id {
+                eyre::bail!(
+                    "menu was fetched for diet #{fetched_for}, refusing to submit changes to diet #{diet_id}"
+                );
+            }
+        }
+        for item in &changes.items {
+            let dish_item = self.get_dish_item(&item.dish_item).ok_or_else(|| {
+                eyre::eyre!("meal {} is not part of diet #{diet_id}", item.dish_item)
+            })?;
+            if !dish_item
+                .options()
+                .iter()
+                .any(|option| option.dish.id == item.dish)
+            {
+                eyre::bail!(
+                    "dish {} is not offered for {} in diet #{diet_id}",
+                    item.dish,
+                    dish_item.meal_type.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks dishes from `changes` as selected, as if they were already saved
+    pub fn apply_changes(&mut self, changes: &ChangeMenuRequest) {
+        for item in &changes.items {
+            if let Some(dish_item) = self
+                .diet_elements
+                .members
+                .iter_mut()
+                .find(|dish_item| dish_item.id == item.dish_item)
+            {
+                dish_item.dish_size.dish = Dish {
+                    id: item.dish.clone(),
+                };
+            }
+        }
+    }
+
+    pub fn attach_ingredients(&mut self, ingredients: &[DishSizeIngredients]) {
+        for option in self
+            .diet_elements
+            .members
+            .iter_mut()
+            .flat_map(|dish_item| dish_item.options.iter_mut())
+        {
+            if let Some(found) = ingredients
+                .iter()
+                .find(|i| i.dish_size_id == option.dish_size_id)
+            {
+                option.ingredients = Some(found.clone());
+            }
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -32,7 +211,7 @@ pub struct DietElements {
     pub members: Vec<DishItem>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct DishItem {
     #[serde(rename = "@id")]
     pub id: String,
@@ -41,20 +220,74 @@ pub struct DishItem {
     pub meal_type: MealType,
     #[serde(rename = "dishSize")]
     pub dish_size: DishSize,
+    /// Set by the kitchen when the meal can't be changed even though the day can
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl DishItem {
+    /// Enabled options, one per dish. The API sometimes lists a dish once per
+    /// size, but menu changes only reference the dish, so sizes aren't a choice.
     pub fn options(&self) -> Vec<&MenuDietOption> {
-        self.options
-            .iter()
-            .filter(|option| option.enabled)
-            .collect()
+        let mut options: Vec<&MenuDietOption> = Vec::new();
+        for option in self.options.iter().filter(|option| option.enabled) {
+            if let Some(kept) = options.iter().find(|kept| kept.dish.id == option.dish.id) {
+                tracing::debug!(
+                    "{}: dish {} listed again as size {} ({}), keeping size {} ({})",
+                    self.meal_type.name,
+                    option.dish.id,
+                    option.dish_size_id,
+                    option.name,
+                    kept.dish_size_id,
+                    kept.name
+                );
+                continue;
+            }
+            options.push(option);
+        }
+        options
     }
 
     pub fn get_dish(&self, dish_id: &str) -> Option<&MenuDietOption> {
         self.options.iter().find(|option| option.dish.id == dish_id)
     }
 
+    /// Index of `dish_id` in [`options`](Self::options), None when it isn't offered
+    pub fn option_position(&self, dish_id: &str) -> Option<usize> {
+        self.options()
+            .iter()
+            .position(|option| option.dish.id == dish_id)
+    }
+
+    /// Servings ordered for this meal, when the subscription allows more than one
+    pub fn quantity(&self) -> Option<u32> {
+        extra_field(&self.extra, &["quantity", "amount", "portions"], |value| {
+            number(value)
+                .filter(|quantity| quantity.fract() == 0.0 && *quantity >= 0.0)
+                .and_then(|quantity| u32::try_from(quantity as u64).ok())
+        })
+    }
+
+    /// Meal name with the number of servings when there is more than one
+    pub fn display_name(&self) -> String {
+        match self.quantity() {
+            Some(quantity) if quantity > 1 => format!("{} (x{quantity})", self.meal_type.name),
+            _ => self.meal_type.name.clone(),
+        }
+    }
+
+    /// Whether there is nothing to choose for this meal
+    pub fn is_locked(&self) -> bool {
+        self.locked || self.options().len() <= 1
+    }
+
+    /// The kitchen's default, the API doesn't mark it so the first option is assumed
+    pub fn default_option(&self) -> Option<&MenuDietOption> {
+        self.options().first().copied()
+    }
+
     pub fn get_selected_option(&self) -> Option<&MenuDietOption> {
         let id = &self.dish_size.dish.id;
         self.options.iter().find(|option| &option.dish.id == id)
@@ -72,19 +305,51 @@ pub struct DishSizeIngredients {
     #[serde(rename = "dishSizeId")]
     pub dish_size_id: i64,
     pub ingredients: Vec<String>,
+    /// When the ingredients were fetched, not part of the response
+    #[serde(rename = "fetchedAt", default, skip_serializing_if = "Option::is_none")]
+    pub fetched_at: Option<DateTime<Local>>,
+    /// Optional fields only some dishes have, read through the accessors
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl DishSizeIngredients {
+    /// Allergens declared for the dish, when the API provides them
+    pub fn allergens(&self) -> Vec<String> {
+        extra_field(&self.extra, &["allergens", "allergenList"], name_list).unwrap_or_default()
+    }
+}
+
+/// Accepts a list of names or of objects with a `name`, anything else is skipped
+fn name_list(value: &serde_json::Value) -> Option<Vec<String>> {
+    let serde_json::Value::Array(items) = value else {
+        return None;
+    };
+    Some(
+        items
+            .iter()
+            .filter_map(|item| match item {
+                serde_json::Value::String(name) => Some(name.clone()),
+                serde_json::Value::Object(fields) => {
+                    fields.get("name")?.as_str().map(str::to_string)
+                }
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct DishSize {
     pub dish: Dish,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MealType {
     pub name: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MenuDietOption {
     pub name: String,
     pub enabled: bool,
@@ -93,9 +358,210 @@ pub struct MenuDietOption {
     pub dish_size_id: i64,
     #[serde(skip)]
     pub ingredients: Option<DishSizeIngredients>,
+    /// Name in `translate_to`, filled in by [`crate::translate::translate_menu`]
+    #[serde(skip)]
+    pub translation: Option<String>,
+    /// Optional fields only some menus have, read through the accessors
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Nutrition of a dish, or a sum over dishes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct Nutrition {
+    pub calories: f64,
+    pub protein: f64,
+    pub fat: f64,
+    pub carbohydrates: f64,
+}
+
+impl std::ops::AddAssign for Nutrition {
+    fn add_assign(&mut self, other: Self) {
+        self.calories += other.calories;
+        self.protein += other.protein;
+        self.fat += other.fat;
+        self.carbohydrates += other.carbohydrates;
+    }
+}
+
+impl std::ops::Mul<f64> for Nutrition {
+    type Output = Self;
+
+    fn mul(self, factor: f64) -> Self {
+        Self {
+            calories: self.calories * factor,
+            protein: self.protein * factor,
+            fat: self.fat * factor,
+            carbohydrates: self.carbohydrates * factor,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Banned<'a> {
+    /// Contains this banned ingredient
+    Contains(&'a str),
+    /// Ingredients are unknown, so it may contain any
+    Unknown,
+}
+
+impl std::fmt::Display for Banned<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Banned::Contains(ingredient) => write!(f, "contains banned {ingredient}"),
+            Banned::Unknown => write!(f, "ingredients unknown"),
+        }
+    }
+}
+
+impl std::fmt::Display for Nutrition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.0} kcal, {:.0} g protein, {:.0} g fat, {:.0} g carbohydrates",
+            self.calories, self.protein, self.fat, self.carbohydrates
+        )
+    }
+}
+
+impl MenuDietOption {
+    pub fn rating(&self) -> Option<f64> {
+        extra_field(&self.extra, &["rating"], number)
+    }
+
+    /// Price of the dish on its own
+    pub fn price(&self) -> Option<f64> {
+        extra_field(&self.extra, &["price"], number)
+    }
+
+    /// Paid on top of the diet for picking this dish
+    pub fn surcharge(&self) -> Option<f64> {
+        extra_field(&self.extra, &["surcharge", "priceChange"], number)
+    }
+
+    pub fn protein(&self) -> Option<f64> {
+        extra_field(&self.extra, &["protein", "proteins"], number)
+    }
+
+    /// Nutrition when the menu lists at least the calories, missing macros count as zero
+    pub fn nutrition(&self) -> Option<Nutrition> {
+        let nutrient = |names: &[&str]| extra_field(&self.extra, names, number);
+        Some(Nutrition {
+            calories: nutrient(&["calories", "kcal", "energy"])?,
+            protein: self.protein().unwrap_or_default(),
+            fat: nutrient(&["fat", "fats"]).unwrap_or_default(),
+            carbohydrates: nutrient(&["carbohydrates", "carbs", "carbohydrate"])
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Name with its translation and the surcharge, when known, e.g. "Stek [Steak] (+12 zł)"
+    pub fn label(&self) -> String {
+        let name = match &self.translation {
+            Some(translation) => format!("{} [{}]", self.name, translation),
+            None => self.name.clone(),
+        };
+        match self.surcharge() {
+            Some(surcharge) if surcharge != 0.0 => format!("{} ({:+} zł)", name, surcharge),
+            _ => name,
+        }
+    }
+
+    /// First of `fragments` found in the name or ingredients, and in the allergens with
+    /// `allergens`, ignoring case
+    pub fn find_fragment<'a>(&self, fragments: &'a [String], allergens: bool) -> Option<&'a str> {
+        let mut contents = vec![self.name.to_lowercase()];
+        if let Some(i) = &self.ingredients {
+            contents.extend(i.ingredients.iter().map(|item| item.to_lowercase()));
+            if allergens {
+                contents.extend(i.allergens().iter().map(|item| item.to_lowercase()));
+            }
+        }
+        fragments
+            .iter()
+            .map(|fragment| fragment.trim())
+            .filter(|fragment| !fragment.is_empty())
+            .find(|fragment| {
+                let fragment = fragment.to_lowercase();
+                contents.iter().any(|item| item.contains(&fragment))
+            })
+    }
+
+    /// Why the option must not be picked with `banned` ingredients, if it must not
+    pub fn banned<'a>(&self, banned: &'a [String]) -> Option<Banned<'a>> {
+        if let Some(ingredient) = self.find_fragment(banned, true) {
+            return Some(Banned::Contains(ingredient));
+        }
+        let any_banned = banned
+            .iter()
+            .any(|ingredient| !ingredient.trim().is_empty());
+        // without ingredients nothing says the dish is safe
+        (any_banned && self.lacks_ingredients()).then_some(Banned::Unknown)
+    }
+
+    /// Whether the ingredients are unknown, either not fetched or listed empty
+    pub fn lacks_ingredients(&self) -> bool {
+        self.ingredients
+            .as_ref()
+            .is_none_or(|i| i.ingredients.is_empty())
+    }
+
+    /// Declared allergens, empty when unknown
+    pub fn allergens(&self) -> Vec<String> {
+        self.ingredients
+            .as_ref()
+            .map(|i| i.allergens())
+            .unwrap_or_default()
+    }
+}
+
+/// Accepts numbers and numeric strings, anything else is treated as missing
+/// so an unexpected shape doesn't break parsing of the whole menu.
+fn number(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::Number(number) => number.as_f64(),
+        serde_json::Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Names of the optional fields the accessors read with [`extra_field`]
+const READ_EXTRA_FIELDS: &[&str] = &[
+    "quantity",
+    "amount",
+    "portions",
+    "allergens",
+    "allergenList",
+    "rating",
+    "price",
+    "surcharge",
+    "priceChange",
+    "protein",
+    "proteins",
+    "calories",
+    "kcal",
+    "energy",
+    "fat",
+    "fats",
+    "carbohydrates",
+    "carbs",
+    "carbohydrate",
+];
+
+/// First of `names` in `extra` that `read` makes sense of. Fields the API may name several
+/// ways are read like this rather than with serde aliases, which fail when two names are present
+fn extra_field<T>(
+    extra: &serde_json::Map<String, serde_json::Value>,
+    names: &[&str],
+    read: impl Fn(&serde_json::Value) -> Option<T>,
+) -> Option<T> {
+    names
+        .iter()
+        .filter_map(|name| extra.get(*name))
+        .find_map(read)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Dish {
     #[serde(rename = "@id")]
     pub id: String,
@@ -112,37 +578,138 @@ impl CalendarDayItems {
     pub fn debug_options(&self) -> String {
         let mut summary = String::new();
         for dish in &self.diet_elements.members {
-            summary.push_str(&format!("{}\n", dish.meal_type.name));
+            summary.push_str(&format!("{}\n", dish.display_name()));
             let selected_option_id = dish.get_selected_option().map(|o| o.dish.id.clone()).unwrap_or_default();
-            for option in &dish.options {
-                if !option.enabled {
-                    continue;
-                }
+            for option in dish.options() {
                 summary.push_str(&format!(
                     "  [{}] {}\n",
                     if option.dish.id == selected_option_id { "*" } else { " " },
-                    option.name,
+                    option.label(),
                 ));
+                let allergens = option.allergens();
+                if !allergens.is_empty() {
+                    summary.push_str(&format!("        allergens: {}\n", allergens.join(", ")));
+                }
                 // summary.push_str(&format!("        {}\n", option.ingredients.join(", ")));
             }
         }
         summary
     }
+
+    /// Lists response fields that are not part of the model, so add-ons and
+    /// other extras the API offers are at least visible. JSON-LD metadata and
+    /// fields the accessors already read are left out.
+    pub fn debug_extra_fields(&self) -> String {
+        let unmodelled =
+            |key: &&String| !key.starts_with('@') && !READ_EXTRA_FIELDS.contains(&key.as_str());
+        let mut summary = String::new();
+        for (key, value) in self.extra.iter().filter(|(key, _)| unmodelled(key)) {
+            summary.push_str(&format!("  {}: {}\n", key, preview_value(value)));
+        }
+        for dish in &self.diet_elements.members {
+            for (key, value) in dish.extra.iter().filter(|(key, _)| unmodelled(key)) {
+                summary.push_str(&format!(
+                    "  {} / {}: {}\n",
+                    dish.meal_type.name,
+                    key,
+                    preview_value(value)
+                ));
+            }
+        }
+        summary
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+fn preview_value(value: &serde_json::Value) -> String {
+    const MAX_PREVIEW: usize = 80;
+    let text = value.to_string();
+    if text.chars().count() > MAX_PREVIEW {
+        format!("{}...", text.chars().take(MAX_PREVIEW).collect::<String>())
+    } else {
+        text
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct DietsList {
     #[serde(rename = "hydra:member")]
     pub members: Vec<Diet>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Diet {
     pub id: i64,
     #[serde(rename = "firstDeliveryDate")]
     pub first_delivery_date: DateTime<FixedOffset>,
     #[serde(rename = "lastDeliveryDate")]
     pub last_delivery_date: DateTime<FixedOffset>,
+    /// Optional fields only some diets have, read through the accessors
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Accepts weekday names ("Mon", "monday") or ISO numbers (1 is Monday), anything else is skipped
+fn weekdays(value: &serde_json::Value) -> Option<Vec<Weekday>> {
+    let serde_json::Value::Array(items) = value else {
+        return None;
+    };
+    Some(
+        items
+            .iter()
+            .filter_map(|item| match item {
+                serde_json::Value::String(name) => name.parse().ok(),
+                serde_json::Value::Number(number) => number
+                    .as_u64()
+                    .filter(|number| (1..=7).contains(number))
+                    .and_then(|number| Weekday::try_from(number as u8 - 1).ok()),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+impl Diet {
+    /// Meals delivered each day, empty when the API doesn't list them
+    pub fn meal_types(&self) -> Vec<String> {
+        extra_field(
+            &self.extra,
+            &["mealTypes", "meals", "meal_types"],
+            name_list,
+        )
+        .unwrap_or_default()
+    }
+
+    /// Weekdays with a delivery, empty when the API doesn't say
+    pub fn delivery_days(&self) -> Vec<Weekday> {
+        extra_field(
+            &self.extra,
+            &["deliveryDays", "weekdays", "delivery_days"],
+            weekdays,
+        )
+        .unwrap_or_default()
+    }
+
+    /// Whether the diet delivers on the weekday of `date`, assumed when the schedule is unknown
+    pub fn delivers_on(&self, date: NaiveDate) -> bool {
+        let delivery_days = self.delivery_days();
+        delivery_days.is_empty() || delivery_days.contains(&date.weekday())
+    }
+
+    /// Meals of the diet that are not on the day's menu, usually a sign the menu fetched partially
+    pub fn missing_meal_types(&self, calendar_day_items: &CalendarDayItems) -> Vec<String> {
+        self.meal_types()
+            .into_iter()
+            .filter(|meal_type| {
+                !calendar_day_items
+                    .diet_elements
+                    .members
+                    .iter()
+                    .any(|dish_item| {
+                        dish_item.meal_type.name.to_lowercase() == meal_type.to_lowercase()
+                    })
+            })
+            .collect()
+    }
 }
 
 impl DietsList {
@@ -152,6 +719,16 @@ impl DietsList {
             .find(|diet| diet.first_delivery_date <= *date && *date <= diet.last_delivery_date)
     }
 
+    /// Whether no diet delivers on the weekday of `date`, only when every diet
+    /// lists its delivery days
+    pub fn is_delivery_gap(&self, date: NaiveDate) -> bool {
+        !self.members.is_empty()
+            && self
+                .members
+                .iter()
+                .all(|diet| !diet.delivery_days().is_empty() && !diet.delivers_on(date))
+    }
+
     pub fn diets_in_time_range(&self, from: &DateTime<Local>, to: &DateTime<Local>) -> Vec<&Diet> {
         self.members
             .iter()
@@ -188,14 +765,114 @@ pub enum DietDayState {
     WithoutMenu,
 }
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
 pub struct ChangeMenuRequest {
     pub items: Vec<ChangeMenuItem>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ChangeMenuItem {
     pub dish: String,
     #[serde(rename = "dishItem")]
     pub dish_item: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn option(name: &str, enabled: bool, dish_id: &str, dish_size_id: i64) -> serde_json::Value {
+        json!({
+            "name": name,
+            "enabled": enabled,
+            "dish": { "@id": dish_id },
+            "dishSizeId": dish_size_id,
+        })
+    }
+
+    fn dish_item(id: &str, options: Vec<serde_json::Value>) -> DishItem {
+        let selected = options[0]["dish"]["@id"].clone();
+        serde_json::from_value(json!({
+            "@id": id,
+            "mealType": { "name": "Obiad" },
+            "dishSize": { "dish": { "@id": selected } },
+            "options": options,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn disabled_dish_is_not_offered() {
+        let dish_item = dish_item(
+            "/diet-elements/1",
+            vec![
+                option("Owsianka", true, "/dishes/1", 11),
+                option("Pancakes", false, "/dishes/2", 12),
+                option("Jajecznica", true, "/dishes/3", 13),
+            ],
+        );
+        assert_eq!(dish_item.option_position("/dishes/1"), Some(0));
+        assert_eq!(dish_item.option_position("/dishes/2"), None);
+        assert_eq!(dish_item.option_position("/dishes/3"), Some(1));
+        assert_eq!(dish_item.option_position("/dishes/4"), None);
+    }
+
+    #[test]
+    fn sizes_of_one_dish_are_one_option() {
+        let dish_item = dish_item(
+            "/diet-elements/1",
+            vec![
+                option("Kurczak curry", true, "/dishes/1", 11),
+                option("Łosoś", true, "/dishes/2", 21),
+                option("Kurczak curry XL", true, "/dishes/1", 12),
+            ],
+        );
+        let options = dish_item.options();
+        assert_eq!(options.len(), 2);
+        assert_eq!(options[0].dish_size_id, 11);
+        assert_eq!(dish_item.option_position("/dishes/1"), Some(0));
+        assert_eq!(dish_item.option_position("/dishes/2"), Some(1));
+    }
+
+    #[test]
+    fn repeated_dish_item_id_is_found() {
+        let menu = |ids: [&str; 3]| -> CalendarDayItems {
+            let members = ids
+                .iter()
+                .map(|id| dish_item(id, vec![option("Sałatka", true, "/dishes/1", 11)]))
+                .collect::<Vec<_>>();
+            serde_json::from_value(json!({ "dietElements": { "hydra:member": members } })).unwrap()
+        };
+        let unique = menu(["/diet-elements/1", "/diet-elements/2", "/diet-elements/3"]);
+        assert_eq!(unique.duplicate_dish_item_id(), None);
+        let repeated = menu(["/diet-elements/1", "/diet-elements/2", "/diet-elements/1"]);
+        assert_eq!(repeated.duplicate_dish_item_id(), Some("/diet-elements/1"));
+    }
+
+    #[test]
+    fn quantity_is_read_leniently() {
+        let with = |field: serde_json::Value| {
+            let mut value = serde_json::to_value(dish_item(
+                "/diet-elements/1",
+                vec![option("Sałatka", true, "/dishes/1", 11)],
+            ))
+            .unwrap();
+            value
+                .as_object_mut()
+                .unwrap()
+                .extend(field.as_object().unwrap().clone());
+            serde_json::from_value::<DishItem>(value)
+                .unwrap()
+                .quantity()
+        };
+        assert_eq!(with(json!({ "quantity": 2 })), Some(2));
+        assert_eq!(with(json!({ "quantity": 2.0 })), Some(2));
+        assert_eq!(with(json!({ "amount": "2" })), Some(2));
+        assert_eq!(with(json!({ "portions": "2.0" })), Some(2));
+        assert_eq!(with(json!({ "quantity": 1.5 })), None);
+        assert_eq!(with(json!({ "quantity": 2, "amount": 3 })), Some(2));
+        assert_eq!(with(json!({})), None);
+    }
+}
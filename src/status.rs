@@ -0,0 +1,88 @@
+//! Transient progress messages. The CLI shows them on a single, overwritten
+//! terminal line; library users get them as tracing events instead.
+
+use std::{
+    future::Future,
+    io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static TERMINAL: AtomicBool = AtomicBool::new(false);
+static STATUS_LINE: AtomicBool = AtomicBool::new(true);
+
+tokio::task_local! {
+    static QUIET: bool;
+}
+
+/// Runs `future` with its messages only logged, for background work that
+/// must not draw over an interactive prompt
+pub async fn quietly<F: Future>(future: F) -> F::Output {
+    QUIET.scope(true, future).await
+}
+
+/// Wraps `future` before spawning it, task-locals don't reach spawned tasks by themselves
+pub fn inherit_quiet<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    QUIET.scope(quiet(), future)
+}
+
+fn quiet() -> bool {
+    QUIET.try_with(|quiet| *quiet).unwrap_or(false)
+}
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Show status messages on the terminal instead of logging them
+pub fn set_terminal(enabled: bool) {
+    TERMINAL.store(enabled, Ordering::Relaxed);
+}
+
+/// Show `detail` messages on the terminal too
+pub fn set_verbose(enabled: bool) {
+    VERBOSE.store(enabled, Ordering::Relaxed);
+}
+
+/// Log status messages even in terminal mode, for output that is not a tty
+pub fn set_status_line(enabled: bool) {
+    STATUS_LINE.store(enabled, Ordering::Relaxed);
+}
+
+fn status_line() -> bool {
+    TERMINAL.load(Ordering::Relaxed) && STATUS_LINE.load(Ordering::Relaxed) && !quiet()
+}
+
+pub fn status(txt: &str) {
+    if status_line() {
+        clear_status();
+        print!("{}\r", txt);
+        io::stdout().flush().unwrap();
+    } else if quiet() {
+        tracing::debug!("{}", txt);
+    } else {
+        tracing::info!("{}", txt);
+    }
+}
+
+pub fn clear_status() {
+    if status_line() {
+        print!("\r\x1b[2K");
+        io::stdout().flush().unwrap();
+    }
+}
+
+/// Message worth keeping on screen, unlike `status` it is not overwritten
+pub fn notice(txt: &str) {
+    if TERMINAL.load(Ordering::Relaxed) && !quiet() {
+        clear_status();
+        println!("{}", txt);
+    } else {
+        tracing::info!("{}", txt);
+    }
+}
+
+/// Like `notice`, but only shown in verbose mode
+pub fn detail(txt: &str) {
+    if VERBOSE.load(Ordering::Relaxed) {
+        notice(txt);
+    } else {
+        tracing::debug!("{}", txt);
+    }
+}
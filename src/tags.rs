@@ -0,0 +1,81 @@
+use indexmap::IndexMap;
+
+use crate::ai::UserAdjustment;
+
+/// Keyword rules mapping free text reasons to tags, both English and Polish phrasing
+const TAG_KEYWORDS: &[(&str, &[&str])] = &[
+    ("too_spicy", &["spicy", "too hot", "ostr", "pikant"]),
+    (
+        "too_heavy",
+        &[
+            "heavy", "greasy", "fatty", "ciężk", "ciezk", "tłust", "tlust",
+        ],
+    ),
+    ("too_sweet", &["sweet", "sugary", "słodk", "slodk"]),
+    ("too_bland", &["bland", "tasteless", "mdł", "bez smaku"]),
+    (
+        "not_filling",
+        &["not filling", "too small", "hungry", "mało", "głodn"],
+    ),
+    (
+        "repetitive",
+        &["again", "repeat", "too often", "znowu", "ciągle", "często"],
+    ),
+    ("craving", &["craving", "in the mood", "ochot"]),
+];
+
+const DISLIKE_PHRASES: &[&str] = &[
+    "don't like ",
+    "dont like ",
+    "do not like ",
+    "hate ",
+    "nie lubię ",
+    "nie lubie ",
+];
+
+pub fn extract_tags(reason: &str) -> Vec<String> {
+    let reason = reason.to_lowercase();
+    let mut tags = Vec::new();
+    for (tag, keywords) in TAG_KEYWORDS {
+        if keywords.iter().any(|keyword| reason.contains(keyword)) {
+            tags.push(tag.to_string());
+        }
+    }
+    for phrase in DISLIKE_PHRASES {
+        if let Some(idx) = reason.find(phrase) {
+            let ingredient = reason[idx + phrase.len()..]
+                .split(|c: char| !c.is_alphabetic())
+                .next()
+                .unwrap_or_default();
+            if !ingredient.is_empty() {
+                let tag = format!("disliked_ingredient:{ingredient}");
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+        }
+    }
+    tags
+}
+
+/// How often each tag occurs across adjustments, most frequent first
+pub fn tag_frequencies(adjustments: &[UserAdjustment]) -> IndexMap<String, usize> {
+    let mut frequencies = IndexMap::new();
+    for adjustment in adjustments {
+        // adjustments saved before tagging existed only have the reason text
+        let tags = if adjustment.tags.is_empty() {
+            adjustment
+                .reason
+                .as_deref()
+                .map(extract_tags)
+                .unwrap_or_default()
+        } else {
+            adjustment.tags.clone()
+        };
+        for tag in tags {
+            *frequencies.entry(tag).or_insert(0) += 1;
+        }
+    }
+    frequencies.sort_by(|_, a, _, b| b.cmp(a));
+    frequencies
+}
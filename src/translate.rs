@@ -0,0 +1,69 @@
+//! Glosses of the Polish dish names in another language, cached on disk so
+//! each name is translated only once.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use indexmap::IndexMap;
+
+use crate::{
+    ai,
+    cache::{self, Cache},
+    config, CalendarDayItems,
+};
+
+pub(crate) const TRANSLATIONS_FILE: &str = "translations.json";
+
+/// language -> dish name -> translation
+type Translations = HashMap<String, IndexMap<String, String>>;
+
+static TRANSLATIONS: LazyLock<Mutex<Translations>> =
+    LazyLock::new(|| Mutex::new(cache::load_json(Cache::Translations)));
+
+/// Translates dish names of the menu that have no translation yet and sets the
+/// translation of every option. Names translated before are set even when it fails.
+pub async fn translate_menu(calendar_day_items: &mut CalendarDayItems) -> eyre::Result<()> {
+    let Some(language) = &config::get().translate_to else {
+        return Ok(());
+    };
+    let result = translate_missing(calendar_day_items, language).await;
+    let translations = TRANSLATIONS.lock().unwrap();
+    if let Some(names) = translations.get(language) {
+        for option in calendar_day_items.options_mut() {
+            option.translation = names.get(&option.name).cloned();
+        }
+    }
+    result
+}
+
+async fn translate_missing(
+    calendar_day_items: &CalendarDayItems,
+    language: &str,
+) -> eyre::Result<()> {
+    let mut names = Vec::new();
+    {
+        let translations = TRANSLATIONS.lock().unwrap();
+        let known = translations.get(language);
+        for dish_item in &calendar_day_items.diet_elements.members {
+            for option in dish_item.options() {
+                let translated = known.is_some_and(|known| known.contains_key(&option.name));
+                if !translated && !names.contains(&option.name) {
+                    names.push(option.name.clone());
+                }
+            }
+        }
+    }
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let translated = ai::translate_names(&names, language).await?;
+    let mut translations = TRANSLATIONS.lock().unwrap();
+    translations
+        .entry(language.to_string())
+        .or_default()
+        .extend(names.into_iter().zip(translated));
+    cache::save_json(Cache::Translations, &*translations)
+}